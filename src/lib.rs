@@ -1,7 +1,27 @@
 #[macro_use]
 extern crate log;
 extern crate crc16;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+/// Emits a `tracing::debug!` event when the `tracing` feature is enabled,
+/// and expands to nothing otherwise.
+#[cfg(feature = "tracing")]
+macro_rules! trace_event {
+    ($($arg:tt)*) => { tracing::debug!($($arg)*) };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_event {
+    ($($arg:tt)*) => {};
+}
 
 pub mod xmodem;
 mod xymodem_util;
 pub mod ymodem;
+
+pub use xymodem_util::{
+    calc_checksum, calc_crc, crc16_table, fill_buf, get_byte, get_byte_timeout, Error,
+};
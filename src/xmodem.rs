@@ -1,9 +1,7 @@
 use std::io::{Read, Write};
 pub use xymodem_util::*;
 
-// TODO: Send CAN byte after too many errors
 // TODO: Handle CAN bytes while sending
-// TODO: Implement Error for Error
 
 const SOH: u8 = 0x01;
 const STX: u8 = 0x02;
@@ -12,23 +10,46 @@ const ACK: u8 = 0x06;
 const NAK: u8 = 0x15;
 const CAN: u8 = 0x18;
 const CRC: u8 = 0x43;
+const G: u8 = 0x47;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Copy, Clone, Debug)]
-pub enum Checksum {
-    Standard,
-    CRC16,
-}
-
-#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum BlockLength {
     Standard = 128,
     OneK = 1024,
+    /// 1024-byte blocks streamed without waiting for an ACK after each block
+    /// (XMODEM-1k-G). Only suitable for error-free links; a bad block aborts the transfer.
+    ///
+    /// Its discriminant only tells it apart from `OneK`; use `size()` for the block size.
+    OneKG = 1025,
+}
+
+impl BlockLength {
+    /// The number of data bytes in a block.
+    /// Matches `block_length as usize` for `Standard` and `OneK`.
+    pub fn size(self) -> usize {
+        match self {
+            BlockLength::Standard => 128,
+            BlockLength::OneK | BlockLength::OneKG => 1024,
+        }
+    }
+}
+
+/// Progress of `Xmodem::recv` after the transfer has started.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum RecvState {
+    /// The SOH or STX of the first block was already read while starting the transfer.
+    AwaitingFirst(u8),
+    /// Reading each block's first byte from the device.
+    Receiving,
 }
 
 /// Configuration for the XMODEM transfer.
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct Xmodem {
     /// The number of errors that can occur before the communication is
     /// considered a failure. Errors include unexpected bytes and timeouts waiting for bytes.
@@ -44,16 +65,51 @@ pub struct Xmodem {
     /// so if the message is not a multiple of that size the last block needs to be padded.
     pub pad_byte: u8,
 
-    /// The length of each block. There are only two options: 128-byte blocks (standard
-    ///  XMODEM) or 1024-byte blocks (XMODEM-1k).
+    /// The number of CAN bytes sent when canceling a transfer. Each CAN is followed
+    /// by a backspace, as recommended by the YMODEM specification.
+    pub can_count: u8,
+
+    /// Resets the error count after every block that is received or acknowledged
+    /// cleanly, so that `max_errors` limits consecutive errors rather than the total
+    /// over the whole transfer. Useful on noisy links with long transfers.
+    pub reset_errors_on_success: bool,
+
+    /// The length of each block. There are three options: 128-byte blocks (standard
+    ///  XMODEM), 1024-byte blocks (XMODEM-1k) or streamed 1024-byte blocks (XMODEM-1k-G).
+    ///
+    /// When sending, XMODEM-1k-G is used whenever the receiver requests it with a `G`,
+    /// without changing this field. `OneKG` falls back to 1024-byte blocks otherwise.
+    /// When receiving, setting this to `OneKG` requests XMODEM-1k-G from the sender.
     pub block_length: BlockLength,
 
+    /// When receiving with `Checksum::Standard`, checks whether a first block that fails
+    /// its checksum is valid as a CRC16 block instead, and switches to CRC16 if so.
+    /// This handles senders that ignore the receiver's choice of checksum.
+    pub detect_checksum_mode: bool,
+
+    /// When receiving, drops trailing `pad_byte` bytes from the last block instead of
+    /// writing them to the output. XMODEM has no way of telling padding apart from
+    /// data, so a file that really ends in `pad_byte` will be truncated.
+    pub strip_padding: bool,
+
     /// The checksum mode used by XMODEM. This is determined by the receiver.
+    #[cfg_attr(feature = "serde", serde(skip))]
     checksum_mode: Checksum,
+    /// Whether the receiver requested XMODEM-1k-G when sending.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    streaming: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
     errors: u32,
+    #[cfg_attr(feature = "serde", serde(skip))]
     initial_errors: u32,
 }
 
+impl Default for Xmodem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Xmodem {
     /// Creates the XMODEM config with default parameters.
     pub fn new() -> Self {
@@ -61,13 +117,56 @@ impl Xmodem {
             max_errors: 16,
             max_initial_errors: 16,
             pad_byte: 0x1a,
+            can_count: 8,
+            reset_errors_on_success: false,
             block_length: BlockLength::Standard,
+            detect_checksum_mode: false,
+            strip_padding: false,
             checksum_mode: Checksum::Standard,
+            streaming: false,
             errors: 0,
             initial_errors: 0,
         }
     }
 
+    /// The number of errors counted against `max_errors` during the last transfer.
+    pub fn errors(&self) -> u32 {
+        self.errors
+    }
+
+    /// The number of errors counted against `max_initial_errors` while waiting for
+    /// the last transfer to start.
+    pub fn initial_errors(&self) -> u32 {
+        self.initial_errors
+    }
+
+    /// Cancels a transfer from outside of `send` or `recv`, e.g. after an application
+    /// timeout, by sending `can_count` CAN bytes (and backspaces) to `dev`.
+    ///
+    /// The error counts are reset so the config can be reused for a new transfer.
+    pub fn abort<D: Read + Write>(&mut self, dev: &mut D) -> std::io::Result<()> {
+        self.errors = 0;
+        self.initial_errors = 0;
+        send_cancel(dev, self.can_count)
+    }
+
+    /// Checks the configuration for values that can't make for a working transfer,
+    /// such as `max_errors = 0`, which fails on the very first error.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.max_errors == 0 {
+            return Err(String::from("max_errors must be at least 1"));
+        }
+        if self.max_initial_errors == 0 {
+            return Err(String::from("max_initial_errors must be at least 1"));
+        }
+        if self.can_count == 0 {
+            return Err(String::from(
+                "can_count must be at least 1 to cancel a transfer",
+            ));
+        }
+        Ok(())
+    }
+
     /// Starts the XMODEM transmission.
     ///
     /// `dev` should be the serial communication channel (e.g. the serial device).
@@ -77,32 +176,66 @@ impl Xmodem {
     /// This method has no way of setting the timeout of `dev`, so it's up to the caller
     /// to set the timeout of the device before calling this method. Timeouts on receiving
     /// bytes will be counted against `max_errors`, but timeouts on transmitting bytes
-    /// will be considered a fatal error.
+    /// will be considered a fatal error. While waiting for the receiver to start the
+    /// transfer, errors are counted against `max_initial_errors` instead.
+    #[must_use = "transfer errors must be handled"]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(dev, stream)))]
     pub fn send<D: Read + Write, R: Read>(&mut self, dev: &mut D, stream: &mut R) -> Result<()> {
         self.errors = 0;
+        self.initial_errors = 0;
 
         dbg!("Starting XMODEM transfer");
         (self.start_send(dev))?;
         dbg!("First byte received. Sending stream.");
+        trace_event!(checksum = ?self.checksum_mode, "handshake complete");
         (self.send_stream(dev, stream))?;
         dbg!("Sending EOT");
+        trace_event!(errors = self.errors, "data phase complete, sending EOT");
         (self.finish_send(dev))?;
 
         Ok(())
     }
 
+    /// Sends several streams as back-to-back XMODEM transfers on the same connection.
+    ///
+    /// XMODEM has no batch mode of its own, so the receiver starts each transfer by
+    /// sending NAK, `C` or `G` again after acknowledging the previous EOT. The checksum
+    /// mode and error count are chosen anew for every transfer.
+    /// Returns the number of completed transfers.
+    ///
+    /// See `send` for details on `dev` and timeouts.
+    #[must_use = "transfer errors must be handled"]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(dev, streams)))]
+    pub fn send_batch<D, I>(&mut self, dev: &mut D, streams: I) -> Result<u32>
+    where
+        D: Read + Write,
+        I: IntoIterator<Item = Box<dyn Read>>,
+    {
+        let mut streams_sent = 0;
+        for mut stream in streams {
+            (self.send(dev, &mut stream))?;
+            streams_sent += 1;
+        }
+        Ok(streams_sent)
+    }
+
     /// Receive an XMODEM transmission.
     ///
     /// `dev` should be the serial communication channel (e.g. the serial device).
     /// The received data will be written to `outstream`.
     /// `checksum` indicates which checksum mode should be used; Checksum::Standard is
-    /// a reasonable default.
+    /// a reasonable default. If `block_length` is `BlockLength::OneKG`, CRC16 is always used.
+    ///
+    /// By default every block is written in full, so the padding of the last block ends up
+    /// in `outstream`. Set `strip_padding` to remove it.
     ///
     /// # Timeouts
     /// This method has no way of setting the timeout of `dev`, so it's up to the caller
     /// to set the timeout of the device before calling this method. Timeouts on receiving
     /// bytes will be counted against `max_errors`, but timeouts on transmitting bytes
     /// will be considered a fatal error.
+    #[must_use = "transfer errors must be handled"]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(dev, outstream)))]
     pub fn recv<D: Read + Write, W: Write>(
         &mut self,
         dev: &mut D,
@@ -110,15 +243,20 @@ impl Xmodem {
         checksum: Checksum,
     ) -> Result<()> {
         self.errors = 0;
+        self.initial_errors = 0;
         self.checksum_mode = checksum;
-        let mut handled_first_packet = false;
+        let streaming = matches!(self.block_length, BlockLength::OneKG);
+        if streaming {
+            self.checksum_mode = Checksum::CRC16;
+        }
         dbg!("Starting XMODEM receive");
 
         let first_char;
         loop {
-            (dev.write(&[match self.checksum_mode {
-                Checksum::Standard => NAK,
-                Checksum::CRC16 => CRC,
+            (dev.write_all(&[match (streaming, self.checksum_mode) {
+                (true, _) => G,
+                (false, Checksum::Standard) => NAK,
+                (false, Checksum::CRC16) => CRC,
             }])?);
 
             match get_byte_timeout(dev)? {
@@ -140,15 +278,24 @@ impl Xmodem {
             }
         }
         dbg!("NCG sent. Receiving stream.");
+        trace_event!(checksum = ?self.checksum_mode, "handshake complete");
+        // Wraps from 255 to 0 along with the sender's block numbers
         let mut packet_num: u8 = 1;
+        let mut last_good_pnum: Option<u8> = None;
+        let mut detect_checksum = self.detect_checksum_mode;
+        let mut state = RecvState::AwaitingFirst(first_char);
+        // With `strip_padding`, each block is held back until we know it isn't the last
+        let mut held_block: Vec<u8> = Vec::new();
         loop {
-            match if handled_first_packet {
-                get_byte_timeout(dev)?
-            } else {
-                Some(first_char)
-            } {
+            let next_byte = match state {
+                RecvState::AwaitingFirst(c) => {
+                    state = RecvState::Receiving;
+                    Some(c)
+                }
+                RecvState::Receiving => get_byte_timeout(dev)?,
+            };
+            match next_byte {
                 bt @ Some(SOH) | bt @ Some(STX) => {
-                    handled_first_packet = true;
                     // Handle next packet
                     let packet_size = match bt {
                         Some(SOH) => 128,
@@ -158,14 +305,38 @@ impl Xmodem {
                     let pnum = (get_byte(dev))?; // specified packet number
                     let pnum_1c = (get_byte(dev))?; // same, 1's complemented
                                                     // We'll respond with cancel later if the packet number is wrong
-                    let cancel_packet = packet_num != pnum || (255 - pnum) != pnum_1c;
-                    let mut data: Vec<u8> = Vec::new();
-                    data.resize(packet_size, 0);
+                    let valid_1c = (255 - pnum) == pnum_1c;
+                    // The sender repeats a block if our ACK for it was lost
+                    let duplicate = valid_1c && last_good_pnum == Some(pnum) && pnum != packet_num;
+                    let cancel_packet = !duplicate && (packet_num != pnum || !valid_1c);
+                    let mut data: Vec<u8> = vec![0; packet_size];
                     (dev.read_exact(&mut data))?;
                     let success = match self.checksum_mode {
                         Checksum::Standard => {
                             let recv_checksum = (get_byte(dev))?;
-                            calc_checksum(&data) == recv_checksum
+                            if calc_checksum(&data) == recv_checksum {
+                                true
+                            } else if detect_checksum
+                                && (calc_crc(&data) >> 8) as u8 == recv_checksum
+                            {
+                                // The sender may be using CRC16 anyway, in which case the
+                                // byte we read is the high byte of the CRC. Any other block
+                                // is just corrupt, so we don't wait for a low byte that
+                                // may never come.
+                                match get_byte_timeout(dev) {
+                                    Ok(Some(low))
+                                        if calc_crc(&data)
+                                            == ((recv_checksum as u16) << 8) + low as u16 =>
+                                    {
+                                        warn!("Sender is using CRC16, switching checksum mode");
+                                        self.checksum_mode = Checksum::CRC16;
+                                        true
+                                    }
+                                    _ => false,
+                                }
+                            } else {
+                                false
+                            }
                         }
                         Checksum::CRC16 => {
                             let recv_checksum =
@@ -175,33 +346,64 @@ impl Xmodem {
                     };
 
                     if cancel_packet {
-                        (dev.write(&[CAN]))?;
-                        (dev.write(&[CAN]))?;
+                        (send_cancel(dev, self.can_count))?;
                         return Err(Error::Canceled);
                     }
-                    if success {
+                    if success && duplicate {
+                        warn!(
+                            "Received block {} again, acknowledging without writing it",
+                            pnum
+                        );
+                        if !streaming {
+                            (dev.write_all(&[ACK]))?;
+                        }
+                    } else if success {
+                        detect_checksum = false;
+                        trace_event!(block = pnum, "block received");
+                        last_good_pnum = Some(packet_num);
                         packet_num = packet_num.wrapping_add(1);
-                        (dev.write(&[ACK]))?;
-                        (outstream.write_all(&data))?;
+                        if !streaming {
+                            (dev.write_all(&[ACK]))?;
+                        }
+                        if self.strip_padding {
+                            (outstream.write_all(&held_block))?;
+                            held_block = data;
+                        } else {
+                            (outstream.write_all(&data))?;
+                        }
+                        if self.reset_errors_on_success {
+                            self.errors = 0;
+                        }
+                    } else if streaming {
+                        // There are no retransmissions in XMODEM-1k-G
+                        eprint!("Bad CRC for block {} in XMODEM-1k-G transfer", pnum);
+                        (send_cancel(dev, self.can_count))?;
+                        return Err(Error::ExhaustedRetries);
                     } else {
-                        (dev.write(&[NAK]))?;
+                        trace_event!(block = pnum, "bad checksum, requesting retransmit");
+                        (dev.write_all(&[NAK]))?;
                         self.errors += 1;
                     }
                 }
                 Some(EOT) => {
                     // End of file
-                    (dev.write(&[ACK]))?;
+                    trace_event!("EOT received");
+                    (dev.write_all(&[ACK]))?;
+                    let pad_byte = self.pad_byte;
+                    let data_len = held_block.len()
+                        - held_block
+                            .iter()
+                            .rev()
+                            .take_while(|&&b| b == pad_byte)
+                            .count();
+                    (outstream.write_all(&held_block[..data_len]))?;
                     break;
                 }
                 Some(_) => {
                     warn!("Unrecognized symbol!");
                 }
                 None => {
-                    if !handled_first_packet {
-                        self.errors = self.max_errors;
-                    } else {
-                        self.errors += 1;
-                    }
+                    self.errors += 1;
                     warn!("Timeout!")
                 }
             }
@@ -210,6 +412,9 @@ impl Xmodem {
                     "Exhausted max retries ({}) while waiting for ACK for EOT",
                     self.max_errors
                 );
+                if let Err(err) = send_cancel(dev, self.can_count) {
+                    warn!("Error sending CAN bytes: {}", err);
+                }
                 return Err(Error::ExhaustedRetries);
             }
         }
@@ -223,39 +428,49 @@ impl Xmodem {
                     NAK => {
                         dbg!("Standard checksum requested");
                         self.checksum_mode = Checksum::Standard;
+                        self.streaming = false;
                         return Ok(());
                     }
                     CRC => {
                         dbg!("16-bit CRC requested");
                         self.checksum_mode = Checksum::CRC16;
+                        self.streaming = false;
+                        return Ok(());
+                    }
+                    G => {
+                        debug!("XMODEM-1k-G requested");
+                        self.checksum_mode = Checksum::CRC16;
+                        self.streaming = true;
                         return Ok(());
                     }
                     CAN => {
+                        // A CAN is a request from the receiver, not a communication error
                         warn!("Cancel (CAN) byte received");
                         cancels += 1;
+                        if cancels >= 2 {
+                            eprint!(
+                                "Transmission canceled: received two cancel (CAN) bytes \
+                                    at start of XMODEM transfer"
+                            );
+                            return Err(Error::Canceled);
+                        }
+                        continue;
                     }
                     c => warn!("Unknown byte received at start of XMODEM transfer: {}", c),
                 },
                 None => warn!("Timed out waiting for start of XMODEM transfer."),
             }
 
-            self.errors += 1;
+            // Errors while waiting for the receiver don't eat into the data phase's budget
+            self.initial_errors += 1;
 
-            if cancels >= 2 {
-                eprint!(
-                    "Transmission canceled: received two cancel (CAN) bytes \
-                        at start of XMODEM transfer"
-                );
-                return Err(Error::Canceled);
-            }
-
-            if self.errors >= self.max_errors {
+            if self.initial_errors > self.max_initial_errors {
                 eprint!(
                     "Exhausted max retries ({}) at start of XMODEM transfer.",
-                    self.max_errors
+                    self.max_initial_errors
                 );
-                if let Err(err) = dev.write_all(&[CAN]) {
-                    warn!("Error sending CAN byte: {}", err);
+                if let Err(err) = send_cancel(dev, self.can_count) {
+                    warn!("Error sending CAN bytes: {}", err);
                 }
                 return Err(Error::ExhaustedRetries);
             }
@@ -265,57 +480,79 @@ impl Xmodem {
     fn send_stream<D: Read + Write, R: Read>(&mut self, dev: &mut D, stream: &mut R) -> Result<()> {
         let mut block_num = 0u32;
         loop {
-            let mut buff = vec![self.pad_byte; self.block_length as usize + 3];
-            let n = (stream.read(&mut buff[3..]))?;
+            // Everything about this block's size follows from this, including the header byte
+            let packet_size = if self.streaming {
+                1024
+            } else {
+                self.block_length.size()
+            };
+            let mut data = vec![self.pad_byte; packet_size];
+            let n = (fill_buf(stream, &mut data))?;
             if n == 0 {
                 dbg!("Reached EOF");
                 return Ok(());
             }
 
             block_num += 1;
-            buff[0] = match self.block_length {
-                BlockLength::Standard => SOH,
-                BlockLength::OneK => STX,
+            let packet = PacketBuilder {
+                // Only the low byte is sent, so block numbers wrap from 255 to 0
+                block_num: (block_num & 0xFF) as u8,
+                checksum: self.checksum_mode,
+                pad_byte: self.pad_byte,
+            };
+            let buff = if packet_size == 128 {
+                packet.build_128(&data[..n])
+            } else {
+                packet.build_1024(&data[..n])
             };
-            buff[1] = (block_num & 0xFF) as u8;
-            buff[2] = 0xFF - buff[1];
 
-            match self.checksum_mode {
-                Checksum::Standard => {
-                    let checksum = calc_checksum(&buff[3..]);
-                    buff.push(checksum);
-                }
-                Checksum::CRC16 => {
-                    let crc = calc_crc(&buff[3..]);
-                    buff.push(((crc >> 8) & 0xFF) as u8);
-                    buff.push((crc & 0xFF) as u8);
-                }
+            if self.streaming {
+                // No ACK is sent for each block in XMODEM-1k-G
+                debug!("Streaming block {}", block_num);
+                (dev.write_all(&buff))?;
+                continue;
             }
 
-            dbg!("Sending block {}", block_num);
-            (dev.write_all(&buff))?;
-
-            match (get_byte_timeout(dev))? {
-                Some(c) => {
-                    if c == ACK {
-                        dbg!("Received ACK for block {}", block_num);
-                        continue;
-                    } else {
-                        warn!("Expected ACK, got {}", c);
+            // The block is kept until it's acknowledged, so a NAK resends it
+            // without reading from the stream again
+            loop {
+                dbg!("Sending block {}", block_num);
+                (dev.write_all(&buff))?;
+
+                match (get_byte_timeout(dev))? {
+                    Some(c) => {
+                        if c == ACK {
+                            dbg!("Received ACK for block {}", block_num);
+                            trace_event!(block = block_num, "block acknowledged");
+                            if self.reset_errors_on_success {
+                                self.errors = 0;
+                            }
+                            break;
+                        } else {
+                            warn!("Expected ACK, got {}", c);
+                        }
+                        // TODO handle CAN bytes
                     }
-                    // TODO handle CAN bytes
+                    None => warn!("Timeout waiting for ACK for block {}", block_num),
                 }
-                None => warn!("Timeout waiting for ACK for block {}", block_num),
-            }
+                trace_event!(
+                    block = block_num,
+                    errors = self.errors + 1,
+                    "block not acknowledged"
+                );
 
-            self.errors += 1;
+                self.errors += 1;
 
-            if self.errors >= self.max_errors {
-                eprint!(
-                    "Exhausted max retries ({}) while sending block {} in XMODEM transfer",
-                    self.max_errors, block_num
-                );
-                return Err(Error::ExhaustedRetries);
+                if self.errors >= self.max_errors {
+                    eprint!(
+                        "Exhausted max retries ({}) while sending block {} in XMODEM transfer",
+                        self.max_errors, block_num
+                    );
+                    if let Err(err) = send_cancel(dev, self.can_count) {
+                        warn!("Error sending CAN bytes: {}", err);
+                    }
+                    return Err(Error::ExhaustedRetries);
+                }
             }
         }
     }
@@ -328,6 +565,7 @@ impl Xmodem {
                 Some(c) => {
                     if c == ACK {
                         info!("XMODEM transmission successful");
+                        trace_event!("EOT acknowledged");
                         return Ok(());
                     } else {
                         warn!("Expected ACK, got {}", c);
@@ -1,4 +1,171 @@
-use std::io::{self, Read};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const CAN: u8 = 0x18;
+const BS: u8 = 0x08;
+
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Checksum {
+    Standard,
+    CRC16,
+}
+
+/// Builds framed and checksummed XMODEM/YMODEM packets.
+#[derive(Copy, Clone, Debug)]
+pub struct PacketBuilder {
+    /// The block number written to the packet header.
+    pub block_num: u8,
+
+    /// The checksum appended to the data.
+    pub checksum: Checksum,
+
+    /// The byte used to pad data shorter than the block length.
+    pub pad_byte: u8,
+}
+
+impl PacketBuilder {
+    /// Creates a builder for block `block_num`, padding with 0x1a.
+    pub fn new(block_num: u8, checksum: Checksum) -> Self {
+        PacketBuilder {
+            block_num,
+            checksum,
+            pad_byte: 0x1a,
+        }
+    }
+
+    /// Builds a 128-byte block (SOH header).
+    ///
+    /// # Panics
+    /// If `data` is longer than 128 bytes.
+    pub fn build_128(&self, data: &[u8]) -> Vec<u8> {
+        self.build(SOH, 128, data)
+    }
+
+    /// Builds a 1024-byte block (STX header).
+    ///
+    /// # Panics
+    /// If `data` is longer than 1024 bytes.
+    pub fn build_1024(&self, data: &[u8]) -> Vec<u8> {
+        self.build(STX, 1024, data)
+    }
+
+    fn build(&self, header: u8, block_length: usize, data: &[u8]) -> Vec<u8> {
+        assert!(
+            data.len() <= block_length,
+            "{} bytes of data don't fit in a {}-byte block",
+            data.len(),
+            block_length
+        );
+        let mut buff = Vec::with_capacity(block_length + 5);
+        buff.push(header);
+        buff.push(self.block_num);
+        buff.push(0xFF - self.block_num);
+        buff.extend_from_slice(data);
+        buff.resize(block_length + 3, self.pad_byte);
+
+        match self.checksum {
+            Checksum::Standard => {
+                let checksum = calc_checksum(&buff[3..]);
+                buff.push(checksum);
+            }
+            Checksum::CRC16 => {
+                let crc = calc_crc(&buff[3..]);
+                buff.push(((crc >> 8) & 0xFF) as u8);
+                buff.push((crc & 0xFF) as u8);
+            }
+        }
+        buff
+    }
+}
+
+/// The fields of a packet that passed `validate_packet`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PacketInfo<'a> {
+    /// The header byte, SOH for 128-byte blocks or STX for 1024-byte blocks.
+    pub header: u8,
+
+    /// The block number.
+    pub block_num: u8,
+
+    /// The data bytes, without the header or checksum.
+    pub data: &'a [u8],
+}
+
+/// The reasons a packet can fail `validate_packet`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PacketError {
+    /// The buffer is empty.
+    Empty,
+
+    /// The header byte is neither SOH nor STX.
+    InvalidHeader(u8),
+
+    /// The buffer length doesn't match the length implied by the header and checksum mode.
+    InvalidLength { expected: usize, actual: usize },
+
+    /// The block number complement isn't the one's complement of the block number.
+    BlockNumberMismatch { block_num: u8, complement: u8 },
+
+    /// The checksum or CRC doesn't match the data.
+    ChecksumMismatch,
+}
+
+/// Checks that `buf` holds exactly one well-formed XMODEM/YMODEM packet, i.e. the
+/// header byte, the block number and its complement, the data and the checksum or CRC.
+pub fn validate_packet(
+    buf: &[u8],
+    checksum_mode: Checksum,
+) -> std::result::Result<PacketInfo<'_>, PacketError> {
+    let header = match buf.first() {
+        Some(&h) => h,
+        None => return Err(PacketError::Empty),
+    };
+    let data_len = match header {
+        SOH => 128,
+        STX => 1024,
+        h => return Err(PacketError::InvalidHeader(h)),
+    };
+    let checksum_len = match checksum_mode {
+        Checksum::Standard => 1,
+        Checksum::CRC16 => 2,
+    };
+    let expected = 3 + data_len + checksum_len;
+    if buf.len() != expected {
+        return Err(PacketError::InvalidLength {
+            expected,
+            actual: buf.len(),
+        });
+    }
+
+    let block_num = buf[1];
+    let complement = buf[2];
+    if 255 - block_num != complement {
+        return Err(PacketError::BlockNumberMismatch {
+            block_num,
+            complement,
+        });
+    }
+
+    let data = &buf[3..3 + data_len];
+    let valid = match checksum_mode {
+        Checksum::Standard => calc_checksum(data) == buf[3 + data_len],
+        Checksum::CRC16 => {
+            calc_crc(data) == ((buf[3 + data_len] as u16) << 8) + buf[4 + data_len] as u16
+        }
+    };
+    if !valid {
+        return Err(PacketError::ChecksumMismatch);
+    }
+
+    Ok(PacketInfo {
+        header,
+        block_num,
+        data,
+    })
+}
 
 pub fn calc_checksum(data: &[u8]) -> u8 {
     data.iter().fold(0, |x, &y| x.wrapping_add(y))
@@ -8,12 +175,56 @@ pub fn calc_crc(data: &[u8]) -> u16 {
     crc16::State::<crc16::XMODEM>::calculate(data)
 }
 
+static CRC16_TABLE: [u16; 256] = make_crc16_table();
+
+const fn make_crc16_table() -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// The lookup table for CRC-16/XMODEM (polynomial 0x1021, initial value 0), the CRC
+/// computed by `calc_crc`. It's built at compile time.
+pub fn crc16_table() -> &'static [u16; 256] {
+    &CRC16_TABLE
+}
+
 pub fn get_byte<R: Read>(reader: &mut R) -> std::io::Result<u8> {
     let mut buff = [0];
     (reader.read_exact(&mut buff))?;
     Ok(buff[0])
 }
 
+/// Reads from `reader` until `buf` is full or the end of the stream is reached,
+/// returning the number of bytes read. Unlike a single `read`, this doesn't stop
+/// early on readers that return fewer bytes than requested.
+pub fn fill_buf<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
 /// Turns timeout errors into `Ok(None)`
 pub fn get_byte_timeout<R: Read>(reader: &mut R) -> std::io::Result<Option<u8>> {
     match get_byte(reader) {
@@ -28,6 +239,14 @@ pub fn get_byte_timeout<R: Read>(reader: &mut R) -> std::io::Result<Option<u8>>
     }
 }
 
+/// Writes the cancel sequence: `count` CAN bytes followed by `count` backspaces,
+/// which erase the CANs from the remote's line buffer if it isn't in a transfer.
+pub(crate) fn send_cancel<W: Write>(dev: &mut W, count: u8) -> io::Result<()> {
+    (dev.write_all(&vec![CAN; count as usize]))?;
+    (dev.write_all(&vec![BS; count as usize]))?;
+    Ok(())
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
         Error::Io(err)
@@ -44,4 +263,27 @@ pub enum Error {
 
     /// The transmission was canceled by the other end of the channel.
     Canceled,
+
+    /// A field of the YMODEM header block could not be parsed.
+    ParseError(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "I/O error ({:?}): {}", err.kind(), err),
+            Error::ExhaustedRetries => write!(f, "Transfer retries exhausted"),
+            Error::Canceled => write!(f, "Transfer canceled by the other end"),
+            Error::ParseError(ref msg) => write!(f, "Invalid header: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
 }
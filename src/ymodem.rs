@@ -1,9 +1,9 @@
-use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
 pub use xymodem_util::*;
 
-// TODO: Send CAN byte after too many errors
 // TODO: Handle CAN bytes while sending
-// TODO: Implement Error for Error
 
 const SOH: u8 = 0x01;
 const STX: u8 = 0x02;
@@ -15,8 +15,45 @@ const CRC: u8 = 0x43;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// The file information sent in the header block of a YMODEM transfer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecvMetadata {
+    /// The file name, without the terminating null byte.
+    pub file_name: String,
+
+    /// The file size in bytes, or 0 if the sender left it empty or sent 0 because the
+    /// size is unknown. The received data then includes the padding of the last block.
+    pub file_size: u64,
+
+    /// The modification time in seconds since the Unix epoch, if the sender included it.
+    pub mtime: Option<u64>,
+
+    /// The Unix file mode, if the sender included it.
+    pub file_mode: Option<u32>,
+
+    /// The serial number of the sending program, if the sender included it.
+    pub serial_number: Option<u32>,
+}
+
+/// Progress of `Ymodem::recv` after the header block has been received.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum RecvState {
+    /// Receiving data blocks, until the first EOT.
+    Data,
+    /// A block was NAK'd, waiting for the sender to retransmit it.
+    Retransmit,
+    /// The first EOT was NAK'd, waiting for it to be sent again.
+    WaitingSecondEot,
+    /// The second EOT was ACK'd, waiting for the null-filename end frame.
+    WaitingEndFrame,
+    /// The end frame was ACK'd.
+    Done,
+}
+
 /// Configuration for the YMODEM transfer.
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct Ymodem {
     /// The number of errors that can occur before the communication is
     /// considered a failure. Errors include unexpected bytes and timeouts waiting for bytes.
@@ -32,12 +69,35 @@ pub struct Ymodem {
     /// so if the message is not a multiple of that size the last block needs to be padded.
     pub pad_byte: u8,
 
-    /// Ignores all non-digit characters on the file_size string
-    /// in the start frame (Ex. 12345V becomes 12345)
+    /// The number of CAN bytes sent when canceling a transfer. Each CAN is followed
+    /// by a backspace, as recommended by the YMODEM specification.
+    pub can_count: u8,
+
+    /// Resets the error count after every block that is received or acknowledged
+    /// cleanly, so that `max_errors` limits consecutive errors rather than the total
+    /// over the whole transfer. Useful on noisy links with long transfers.
+    pub reset_errors_on_success: bool,
+
+    /// Ignores all non-digit characters in the file size field
+    /// of the start frame (Ex. 12345V becomes 12345)
     pub ignore_non_digits_on_file_size: bool,
 
+    /// Keeps only the last component of the file name in the start frame, splitting
+    /// on both '/' and '\' (Ex. firmware/app.bin becomes app.bin), like `rz -E`
+    pub strip_path_from_filename: bool,
+
+    #[cfg_attr(feature = "serde", serde(skip))]
     errors: u32,
+    #[cfg_attr(feature = "serde", serde(skip))]
     initial_errors: u32,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    session_complete: bool,
+}
+
+impl Default for Ymodem {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Ymodem {
@@ -49,38 +109,137 @@ impl Ymodem {
             max_errors: 16,
             max_initial_errors: 16,
             pad_byte: 0x1a,
+            can_count: 8,
+            reset_errors_on_success: false,
             errors: 0,
             initial_errors: 0,
             ignore_non_digits_on_file_size: false,
+            strip_path_from_filename: false,
+            session_complete: false,
+        }
+    }
+
+    /// The number of errors counted against `max_errors` during the last transfer.
+    /// After `send_batch`, this only counts the errors of the last file.
+    pub fn errors(&self) -> u32 {
+        self.errors
+    }
+
+    /// The number of errors counted against `max_initial_errors` while waiting for
+    /// the last transfer to start.
+    pub fn initial_errors(&self) -> u32 {
+        self.initial_errors
+    }
+
+    /// Whether the receiver acknowledged the null-filename end frame of the last
+    /// `send` or `send_batch`, closing the YMODEM session.
+    pub fn session_complete(&self) -> bool {
+        self.session_complete
+    }
+
+    /// Cancels a transfer from outside of `send` or `recv`, e.g. after an application
+    /// timeout, by sending `can_count` CAN bytes (and backspaces) to `dev`.
+    ///
+    /// The error counts are reset so the config can be reused for a new transfer.
+    pub fn abort<D: Read + Write>(&mut self, dev: &mut D) -> std::io::Result<()> {
+        self.errors = 0;
+        self.initial_errors = 0;
+        self.session_complete = false;
+        send_cancel(dev, self.can_count)
+    }
+
+    /// Checks the configuration for values that can't make for a working transfer,
+    /// such as `max_errors = 0`, which fails on the very first error.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.max_errors == 0 {
+            return Err(String::from("max_errors must be at least 1"));
+        }
+        if self.max_initial_errors == 0 {
+            return Err(String::from("max_initial_errors must be at least 1"));
+        }
+        if self.can_count == 0 {
+            return Err(String::from(
+                "can_count must be at least 1 to cancel a transfer",
+            ));
         }
+        Ok(())
     }
 
     /// Receive an YMODEM transmission.
     ///
     /// `dev` should be the serial communication channel (e.g. the serial device).
-    /// The received data will be written to `outstream`.
-    /// `checksum` indicates which checksum mode should be used; Checksum::Standard is
-    /// a reasonable default.
+    /// The received data will be written to `outstream` as each block arrives.
+    /// The file information from the header block is returned once the transfer completes.
+    /// A single file is received, so the transfer is canceled if the sender starts another one.
     ///
     /// # Timeouts
     /// This method has no way of setting the timeout of `dev`, so it's up to the caller
     /// to set the timeout of the device before calling this method. Timeouts on receiving
     /// bytes will be counted against `max_errors`, but timeouts on transmitting bytes
     /// will be considered a fatal error.
+    #[must_use = "transfer errors must be handled"]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(dev, outstream)))]
     pub fn recv<D: Read + Write, W: Write>(
         &mut self,
         dev: &mut D,
         outstream: &mut W,
-        file_name: &mut String,
-        file_size: &mut u32,
-    ) -> Result<()> {
-        let mut file_buf: Vec<u8> = Vec::new();
+    ) -> Result<RecvMetadata> {
+        self.recv_with(dev, move |_| Ok(outstream))
+    }
 
+    /// Receive an YMODEM transmission into a file in `dir`.
+    ///
+    /// The file is named after the last component of the file name in the header block,
+    /// so the sender can't place it outside of `dir`. The data is first written to a file
+    /// with a `.part` suffix, which replaces any existing file of that name once the transfer
+    /// succeeds and is removed if it fails.
+    /// See `recv` for details on `dev` and timeouts.
+    #[must_use = "transfer errors must be handled"]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(dev)))]
+    pub fn recv_to_dir<D: Read + Write>(
+        &mut self,
+        dev: &mut D,
+        dir: &Path,
+    ) -> Result<RecvMetadata> {
+        let mut staged = None;
+        let result = self.recv_with(dev, |file_name| {
+            let base_name = file_name.rsplit(['/', '\\']).next().unwrap_or(file_name);
+            if base_name.is_empty() || base_name == "." || base_name == ".." {
+                return Err(Error::ParseError(format!(
+                    "File name {:?} can't be used to create a file",
+                    file_name
+                )));
+            }
+            let part_path = dir.join(format!("{}.part", base_name));
+            let file = (File::create(&part_path))?;
+            staged = Some((part_path, dir.join(base_name)));
+            Ok(file)
+        });
+
+        if let Some((part_path, path)) = staged {
+            if result.is_ok() {
+                (fs::rename(&part_path, &path))?;
+            } else if let Err(err) = fs::remove_file(&part_path) {
+                warn!("Error removing partial file {:?}: {}", part_path, err);
+            }
+        }
+        result
+    }
+
+    /// Receives a transmission, writing the data to the stream that `open_output`
+    /// returns for the file name in the header block.
+    fn recv_with<D, W, F>(&mut self, dev: &mut D, open_output: F) -> Result<RecvMetadata>
+    where
+        D: Read + Write,
+        W: Write,
+        F: FnOnce(&str) -> Result<W>,
+    {
         self.errors = 0;
+        self.initial_errors = 0;
         dbg!("Starting YMODEM receive");
         // Initialize transfer
         loop {
-            (dev.write(&[CRC])?);
+            (dev.write_all(&[CRC])?);
 
             match get_byte_timeout(dev) {
                 Ok(v) => {
@@ -104,9 +263,7 @@ impl Ymodem {
         // First packet
         // In YModem the header packet is 0
         let mut packet_num: u8 = 0;
-        let mut file_name_buf: Vec<u8> = Vec::new();
-        let mut file_size_buf: Vec<u8> = Vec::new();
-        let mut padding_buf: Vec<u8> = Vec::new();
+        let mut header_buf: Vec<u8> = vec![0; 128];
 
         loop {
             let pnum = (get_byte(dev))?; // specified packet number
@@ -114,83 +271,124 @@ impl Ymodem {
                                             // We'll respond with cancel later if the packet number is wrong
             let cancel_packet = packet_num != pnum || (255 - pnum) != pnum_1c;
 
-            loop {
-                let b = get_byte(dev)?;
-                file_name_buf.push(b);
-                if b == 0x00 {
-                    break;
-                };
-            }
-            *file_name = String::from(
-                std::str::from_utf8(&file_name_buf[0..file_name_buf.len() - 1]).unwrap(),
-            );
-
-            loop {
-                let b = get_byte(dev)?;
-                file_size_buf.push(b);
-                if b == 0x00 {
-                    break;
-                };
-            }
-
-            // We read the padding
-            // The 2 is the 2 zeroes
-            for _ in 0..(128 - file_name_buf.len() - file_size_buf.len()) {
-                padding_buf.push(get_byte(dev)?);
-            }
-
+            (dev.read_exact(&mut header_buf))?;
             let recv_checksum = (((get_byte(dev))? as u16) << 8) + (get_byte(dev))? as u16;
-
-            let mut data_buf: Vec<u8> = Vec::new();
-            data_buf.extend(&file_name_buf);
-            data_buf.extend(&file_size_buf);
-            data_buf.extend(&padding_buf);
-
-            let success = calc_crc(&mut data_buf) == recv_checksum;
+            let success = calc_crc(&header_buf) == recv_checksum;
 
             if cancel_packet {
-                (dev.write(&[CAN]))?;
-                (dev.write(&[CAN]))?;
+                (send_cancel(dev, self.can_count))?;
                 return Err(Error::Canceled);
             }
             if !success {
-                (dev.write(&[NAK]))?;
-                self.errors += 1;
+                self.initial_errors += 1;
+                if self.initial_errors > self.max_initial_errors {
+                    eprint!(
+                        "Exhausted max retries ({}) while receiving the header block",
+                        self.max_initial_errors
+                    );
+                    if let Err(err) = send_cancel(dev, self.can_count) {
+                        warn!("Error sending CAN bytes: {}", err);
+                    }
+                    return Err(Error::ExhaustedRetries);
+                }
+                (dev.write_all(&[NAK]))?;
+                // The sender repeats the whole header, starting with its SOH
+                loop {
+                    match (get_byte_timeout(dev))? {
+                        Some(SOH) => break,
+                        Some(_) => {}
+                        None => {
+                            self.initial_errors += 1;
+                            if self.initial_errors > self.max_initial_errors {
+                                eprint!(
+                                    "Exhausted max retries ({}) while waiting for the header block",
+                                    self.max_initial_errors
+                                );
+                                if let Err(err) = send_cancel(dev, self.can_count) {
+                                    warn!("Error sending CAN bytes: {}", err);
+                                }
+                                return Err(Error::ExhaustedRetries);
+                            }
+                            // Our NAK may have been lost
+                            (dev.write_all(&[NAK]))?;
+                        }
+                    }
+                }
             } else {
                 // First packet received succesfully
+                trace_event!("header block received");
                 packet_num = packet_num.wrapping_add(1);
-                (dev.write(&[ACK]))?;
-                (dev.write(&[CRC]))?;
+                (dev.write_all(&[ACK]))?;
+                (dev.write_all(&[CRC]))?;
                 break;
             }
         }
 
-        let mut file_size_str =
-            std::string::String::from_utf8(file_size_buf[0..file_size_buf.len() - 1].to_vec())
-                .unwrap();
+        // The header is only parsed once its CRC has been verified.
+        // It holds the null-terminated file name followed by the null-terminated size.
+        let mut header_fields = header_buf.split(|&b| b == 0x00);
+        let file_name_buf = header_fields.next().unwrap_or(&[]);
+        let file_size_buf = header_fields.next().unwrap_or(&[]);
+
+        let file_name = match std::str::from_utf8(file_name_buf) {
+            Ok(v) if self.strip_path_from_filename => {
+                String::from(v.rsplit(['/', '\\']).next().unwrap_or(v))
+            }
+            Ok(v) => String::from(v),
+            Err(_) => {
+                return Err(Error::ParseError(String::from(
+                    "File name in header is not valid UTF-8",
+                )))
+            }
+        };
+        let file_size_str = match String::from_utf8(file_size_buf.to_vec()) {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(Error::ParseError(String::from(
+                    "File size in header is not valid UTF-8",
+                )))
+            }
+        };
+
+        // The size is followed by optional fields, all separated by spaces
+        let mut fields = file_size_str.split(' ');
+        let mut size_field = String::from(fields.next().unwrap_or(""));
         if self.ignore_non_digits_on_file_size {
-            file_size_str = file_size_str.chars().filter(|c| c.is_digit(10)).collect();
+            size_field = size_field.chars().filter(|c| c.is_ascii_digit()).collect();
         }
-
-        let file_size_num: u32 = match file_size_str.parse::<u32>() {
+        // An empty or zero size means the sender doesn't know it
+        let file_size_num: u64 = match size_field.parse::<u64>() {
+            _ if size_field.is_empty() => 0,
             Ok(v) => v,
-            // If the first parse fails, we try everything before the space
-            // if that fails too, then we panic
-            _ => file_size_str
-                .split(" ")
-                .next()
-                .unwrap()
-                .parse::<u32>()
-                .unwrap(),
+            Err(_) => {
+                return Err(Error::ParseError(format!(
+                    "File size field is not a valid decimal integer: {:?}",
+                    size_field
+                )))
+            }
         };
-        *file_size = file_size_num;
 
-        let num_of_packets = (file_size_num as f32 / 1024.0).ceil() as u32;
-        let final_packet = num_of_packets + 2;
-        let mut received_first_eot = false;
+        // Everything after the size is optional and encoded in octal
+        let mtime = fields.next().and_then(|f| u64::from_str_radix(f, 8).ok());
+        let file_mode = fields.next().and_then(|f| u32::from_str_radix(f, 8).ok());
+        let serial_number = fields.next().and_then(|f| u32::from_str_radix(f, 8).ok());
+        trace_event!(file_name = %file_name, file_size = file_size_num, "header parsed");
+        let mut outstream = match open_output(&file_name) {
+            Ok(v) => v,
+            Err(err) => {
+                if let Err(err) = send_cancel(dev, self.can_count) {
+                    warn!("Error sending CAN bytes: {}", err);
+                }
+                return Err(err);
+            }
+        };
 
-        for range in 0..(num_of_packets + 3) {
-            dbg!(range);
+        // Blocks are written as they arrive, and the padding after the
+        // declared file size is dropped. Without a size, everything up to the EOT is written.
+        let size_known = file_size_num > 0;
+        let mut bytes_written: u64 = 0;
+        let mut state = RecvState::Data;
+        while state != RecvState::Done {
             match get_byte_timeout(dev)? {
                 bt @ Some(SOH) | bt @ Some(STX) => {
                     // Handle next packet
@@ -203,48 +401,108 @@ impl Ymodem {
                     let pnum_1c = (get_byte(dev))?; // same, 1's complemented
                                                     // We'll respond with cancel later if the packet number is wrong
 
-                    let cancel_packet = match range {
-                        // Final packet num is 0
-                        cp if cp == final_packet => 0x00 != pnum || (255 - pnum) != pnum_1c,
-                        _ => packet_num != pnum || (255 - pnum) != pnum_1c,
+                    let expected_pnum = match state {
+                        // The end frame is numbered like a header
+                        RecvState::WaitingEndFrame => 0x00,
+                        _ => packet_num,
                     };
-                    let mut data: Vec<u8> = Vec::new();
-                    data.resize(packet_size, 0);
+                    // A wrong packet number may be a single corrupted byte,
+                    // so it's retried like a bad checksum
+                    let valid_1c = (255 - pnum) == pnum_1c;
+                    let valid_pnum = expected_pnum == pnum && valid_1c;
+                    // The sender repeats a block if our ACK for it was lost
+                    let duplicate = valid_1c
+                        && state != RecvState::WaitingEndFrame
+                        && pnum == packet_num.wrapping_sub(1);
+                    let mut data: Vec<u8> = vec![0; packet_size];
                     (dev.read_exact(&mut data))?;
                     let recv_checksum = (((get_byte(dev))? as u16) << 8) + (get_byte(dev))? as u16;
-                    let success = calc_crc(&data) == recv_checksum;
-
-                    if cancel_packet {
-                        (dev.write(&[CAN]))?;
-                        (dev.write(&[CAN]))?;
-                        return Err(Error::Canceled);
-                    }
-                    if success {
+                    let valid_crc = calc_crc(&data) == recv_checksum;
+                    let success = valid_pnum && valid_crc;
+
+                    if success && state == RecvState::WaitingEndFrame {
+                        // Only a header with an empty file name ends the batch.
+                        // Another file's header can't be received into the same `outstream`.
+                        if data[0] != 0x00 {
+                            if let Err(err) = send_cancel(dev, self.can_count) {
+                                warn!("Error sending CAN bytes: {}", err);
+                            }
+                            return Err(Error::ParseError(String::from(
+                                "Expected an end frame, got the header of another file",
+                            )));
+                        }
+                        trace_event!("end frame received");
+                        (dev.write_all(&[ACK]))?;
+                        state = RecvState::Done;
+                    } else if duplicate && valid_crc {
+                        warn!(
+                            "Received block {} again, acknowledging without writing it",
+                            pnum
+                        );
+                        (dev.write_all(&[ACK]))?;
+                    } else if state == RecvState::Retransmit && pnum != expected_pnum {
+                        // A block that was already on its way when we sent the NAK.
+                        // The retransmit has been requested, so it's dropped without another NAK.
+                        warn!(
+                            "Expected retransmit of block {}, got {}",
+                            expected_pnum, pnum
+                        );
+                        self.errors += 1;
+                    } else if success {
+                        trace_event!(block = pnum, "block received");
+                        state = RecvState::Data;
                         packet_num = packet_num.wrapping_add(1);
-                        (dev.write(&[ACK]))?;
-                        (file_buf.write_all(&data))?;
+                        let n = if size_known {
+                            std::cmp::min(file_size_num - bytes_written, data.len() as u64)
+                        } else {
+                            data.len() as u64
+                        };
+                        (outstream.write_all(&data[..n as usize]))?;
+                        bytes_written += n;
+                        (dev.write_all(&[ACK]))?;
+                        if self.reset_errors_on_success {
+                            self.errors = 0;
+                        }
+                    } else if !valid_pnum {
+                        warn!("Expected block {}, got {}", expected_pnum, pnum);
+                        trace_event!(block = pnum, "bad block number, requesting retransmit");
+                        (dev.write_all(&[NAK]))?;
+                        self.errors += 1;
                     } else {
-                        (dev.write(&[NAK]))?;
+                        trace_event!(block = pnum, "bad checksum, requesting retransmit");
+                        (dev.write_all(&[NAK]))?;
                         self.errors += 1;
+                        if state == RecvState::Data {
+                            state = RecvState::Retransmit;
+                        }
                     }
                 }
-                Some(EOT) => {
-                    packet_num = packet_num.wrapping_add(1);
+                Some(EOT) => match state {
                     // End of file
-                    if !received_first_eot {
-                        (dev.write(&[NAK]))?;
-                        received_first_eot = true;
-                    } else {
-                        (dev.write(&[ACK]))?;
-                        (dev.write(&[CRC]))?;
+                    RecvState::Data | RecvState::Retransmit => {
+                        trace_event!("first EOT received");
+                        (dev.write_all(&[NAK]))?;
+                        state = RecvState::WaitingSecondEot;
                     }
-                }
+                    // Also answers a repeated EOT if our ACK was lost
+                    RecvState::WaitingSecondEot | RecvState::WaitingEndFrame => {
+                        trace_event!("second EOT received");
+                        (dev.write_all(&[ACK]))?;
+                        (dev.write_all(&[CRC]))?;
+                        state = RecvState::WaitingEndFrame;
+                    }
+                    RecvState::Done => unreachable!(),
+                },
                 Some(_) => {
                     warn!("Unrecognized symbol!");
                 }
                 None => {
                     self.errors += 1;
-                    warn!("Timeout!")
+                    warn!("Timeout!");
+                    if state == RecvState::Retransmit {
+                        // Our NAK may have been lost
+                        (dev.write_all(&[NAK]))?;
+                    }
                 }
             }
             if self.errors >= self.max_errors {
@@ -252,13 +510,50 @@ impl Ymodem {
                     "Exhausted max retries ({}) while waiting for ACK for EOT",
                     self.max_errors
                 );
+                if let Err(err) = send_cancel(dev, self.can_count) {
+                    warn!("Error sending CAN bytes: {}", err);
+                }
                 return Err(Error::ExhaustedRetries);
             }
         }
 
-        outstream
-            .write_all(&file_buf[0..file_size_num as usize])
-            .unwrap();
+        if size_known && bytes_written < file_size_num {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "received fewer bytes than declared in header",
+            )));
+        }
+        Ok(RecvMetadata {
+            file_name,
+            file_size: file_size_num,
+            mtime,
+            file_mode,
+            serial_number,
+        })
+    }
+
+    /// Receive an YMODEM transmission, storing the file name and size from the
+    /// header block in `file_name` and `file_size`.
+    ///
+    /// Files of 4 GiB or more don't fit in `file_size`, so they are reported as a
+    /// `ParseError` once the transfer completes.
+    #[deprecated(note = "use `recv`, which returns all the header fields")]
+    pub fn recv_legacy<D: Read + Write, W: Write>(
+        &mut self,
+        dev: &mut D,
+        outstream: &mut W,
+        file_name: &mut String,
+        file_size: &mut u32,
+    ) -> Result<()> {
+        let metadata = (self.recv(dev, outstream))?;
+        if metadata.file_size > u64::from(u32::MAX) {
+            return Err(Error::ParseError(format!(
+                "File size in header does not fit in 32 bits: {}",
+                metadata.file_size
+            )));
+        }
+        *file_name = metadata.file_name;
+        *file_size = metadata.file_size as u32;
         Ok(())
     }
 
@@ -266,33 +561,127 @@ impl Ymodem {
     ///
     /// `dev` should be the serial communication channel (e.g. the serial device).
     /// `stream` should be the message to send (e.g. a file).
+    /// `file_name` and `file_size_in_bytes` are sent to the receiver in the header block.
+    /// The file name must be printable ASCII and fit in the header block along with the
+    /// size, otherwise the transfer is canceled with an `InvalidInput` error.
     ///
     /// # Timeouts
     /// This method has no way of setting the timeout of `dev`, so it's up to the caller
     /// to set the timeout of the device before calling this method. Timeouts on receiving
     /// bytes will be counted against `max_errors`, but timeouts on transmitting bytes
     /// will be considered a fatal error.
-    pub fn send<D: Read + Write, R: Read>(
+    #[must_use = "transfer errors must be handled"]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(dev, stream, file_name)))]
+    pub fn send<D: Read + Write, R: Read, N: Into<String>>(
         &mut self,
         dev: &mut D,
         stream: &mut R,
-        file_name: String,
+        file_name: N,
         file_size_in_bytes: u64,
     ) -> Result<()> {
         self.errors = 0;
-        let packets_to_send = f64::ceil(file_size_in_bytes as f64 / 1024.0) as u32;
-        let last_packet_size = file_size_in_bytes % 1024;
+        self.session_complete = false;
 
         dbg!("Starting YMODEM transfer");
         (self.start_send(dev))?;
         dbg!("First byte received. Sending start frame.");
+        trace_event!("handshake complete");
+        (self.send_file(dev, stream, file_name.into(), file_size_in_bytes, false))?;
+        (self.send_end_frame(dev, true))?;
+        trace_event!("end frame acknowledged");
+
+        Ok(())
+    }
+
+    /// Sends several files in a single YMODEM batch session.
+    ///
+    /// `files` yields the file name, the file size in bytes and the stream to read each
+    /// file from. After the last file, the null-filename end frame closes the session.
+    /// Returns the number of files that were transferred, which is 0 if `files` is empty.
+    ///
+    /// The error count is reset for every file, so `max_errors` applies to each file
+    /// separately.
+    ///
+    /// See `send` for details on `dev` and timeouts.
+    #[must_use = "transfer errors must be handled"]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(dev, files)))]
+    pub fn send_batch<D, I>(&mut self, dev: &mut D, files: I) -> Result<u32>
+    where
+        D: Read + Write,
+        I: IntoIterator<Item = (String, u64, Box<dyn Read>)>,
+    {
+        self.errors = 0;
+        self.session_complete = false;
+
+        debug!("Starting YMODEM batch transfer");
+        (self.start_send(dev))?;
+        trace_event!("handshake complete");
+        let mut files = files.into_iter().peekable();
+        let mut files_sent = 0;
+        while let Some((file_name, file_size_in_bytes, mut stream)) = files.next() {
+            // Each file gets the full error budget
+            self.errors = 0;
+            // The receiver asks for the next header with a C, but the end frame
+            // waits for its own C
+            let batch_mode = files.peek().is_some();
+            (self.send_file(dev, &mut stream, file_name, file_size_in_bytes, batch_mode))?;
+            files_sent += 1;
+        }
+        (self.send_end_frame(dev, files_sent > 0))?;
+        trace_event!(files = files_sent, "end frame acknowledged");
+
+        Ok(files_sent)
+    }
+
+    /// Sends the start frame, the data and the EOTs of a single file, once the
+    /// receiver has asked for it with a C.
+    fn send_file<D: Read + Write, R: Read>(
+        &mut self,
+        dev: &mut D,
+        stream: &mut R,
+        file_name: String,
+        file_size_in_bytes: u64,
+        batch_mode: bool,
+    ) -> Result<()> {
+        trace_event!(file_name = %file_name, "starting transfer");
+        let packets_to_send = f64::ceil(file_size_in_bytes as f64 / 1024.0) as u32;
+        let last_packet_size = file_size_in_bytes % 1024;
+
         (self.send_start_frame(dev, file_name, file_size_in_bytes))?;
         dbg!("Start frame acknowledged. Sending stream.");
+        trace_event!(blocks = packets_to_send, "start frame acknowledged");
         (self.send_stream(dev, stream, packets_to_send, last_packet_size))?;
         dbg!("Sending EOT");
-        (self.finish_send(dev))?;
+        trace_event!(errors = self.errors, "data phase complete, sending EOT");
+        self.finish_send(dev, batch_mode)
+    }
 
-        Ok(())
+    /// Sends the file at `path` as a YMODEM transmission.
+    ///
+    /// The file name sent in the header block is the last component of `path`, and the
+    /// file size is taken from the file's metadata. See `send` for details.
+    #[must_use = "transfer errors must be handled"]
+    pub fn send_path<D: Read + Write>(&mut self, dev: &mut D, path: &Path) -> Result<()> {
+        let mut file = (File::open(path))?;
+        let file_size = (file.metadata())?.len();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        self.send_buffered(dev, &mut file, file_name, file_size)
+    }
+
+    /// Like `send`, but reads `stream` through a `BufReader` sized for several blocks.
+    ///
+    /// This cuts down on the `read` calls made on unbuffered readers such as a `File`.
+    /// Readers that already implement `BufRead` can be passed to `send` directly.
+    #[must_use = "transfer errors must be handled"]
+    pub fn send_buffered<D: Read + Write, R: Read, N: Into<String>>(
+        &mut self,
+        dev: &mut D,
+        stream: &mut R,
+        file_name: N,
+        file_size_in_bytes: u64,
+    ) -> Result<()> {
+        let mut reader = BufReader::with_capacity(8 * 1024, stream);
+        self.send(dev, &mut reader, file_name, file_size_in_bytes)
     }
 
     fn start_send<D: Read + Write>(&mut self, dev: &mut D) -> Result<()> {
@@ -305,8 +694,17 @@ impl Ymodem {
                         return Ok(());
                     }
                     CAN => {
+                        // A CAN is a request from the receiver, not a communication error
                         warn!("Cancel (CAN) byte received");
                         cancels += 1;
+                        if cancels >= 2 {
+                            eprint!(
+                                "Transmission canceled: received two cancel (CAN) bytes \
+                                    at start of YMODEM transfer"
+                            );
+                            return Err(Error::Canceled);
+                        }
+                        continue;
                     }
                     c => warn!("Unknown byte received at start of YMODEM transfer: {}", c),
                 },
@@ -315,21 +713,13 @@ impl Ymodem {
 
             self.errors += 1;
 
-            if cancels >= 2 {
-                eprint!(
-                    "Transmission canceled: received two cancel (CAN) bytes \
-                        at start of YMODEM transfer"
-                );
-                return Err(Error::Canceled);
-            }
-
             if self.errors >= self.max_errors {
                 eprint!(
                     "Exhausted max retries ({}) at start of YMODEM transfer.",
                     self.max_errors
                 );
-                if let Err(err) = dev.write_all(&[CAN]) {
-                    warn!("Error sending CAN byte: {}", err);
+                if let Err(err) = send_cancel(dev, self.can_count) {
+                    warn!("Error sending CAN bytes: {}", err);
                 }
                 return Err(Error::ExhaustedRetries);
             }
@@ -342,27 +732,35 @@ impl Ymodem {
         file_name: String,
         file_size_in_bytes: u64,
     ) -> Result<()> {
-        let mut buff = vec![0x00; 128 as usize + 3];
-        buff[0] = SOH;
-        buff[1] = 0x00;
-        buff[2] = 0xFF;
-
-        let mut curr_buff_idx = 3;
-        for byte in file_name.as_bytes() {
-            buff[curr_buff_idx] = *byte;
-            curr_buff_idx += 1;
+        // The file name and the size in decimal, each terminated by a null byte
+        let mut data = Vec::new();
+        data.extend_from_slice(file_name.as_bytes());
+        data.push(0x00);
+        data.extend_from_slice(file_size_in_bytes.to_string().as_bytes());
+        data.push(0x00);
+
+        // A null byte would end the name early, and receivers disagree on how to
+        // decode anything outside of ASCII
+        let printable = file_name.bytes().all(|b| b == b' ' || b.is_ascii_graphic());
+        if !printable || data.len() > 128 {
+            if let Err(err) = send_cancel(dev, self.can_count) {
+                warn!("Error sending CAN bytes: {}", err);
+            }
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "File name {:?} is not printable ASCII or doesn't fit in the header",
+                    file_name
+                ),
+            )));
         }
 
-        // We leave one 0 to indicate the name ends here
-        curr_buff_idx += 1;
-
-        for byte in format!("{:x}", file_size_in_bytes).as_bytes() {
-            buff[curr_buff_idx] = *byte;
+        let buff = PacketBuilder {
+            block_num: 0,
+            checksum: Checksum::CRC16,
+            pad_byte: 0x00,
         }
-
-        let crc = calc_crc(&buff[3..]);
-        buff.push(((crc >> 8) & 0xFF) as u8);
-        buff.push((crc & 0xFF) as u8);
+        .build_128(&data);
 
         (dev.write_all(&buff))?;
 
@@ -372,6 +770,9 @@ impl Ymodem {
                     if c == ACK {
                         dbg!("Received ACK for start frame");
                         break;
+                    } else if c == NAK {
+                        warn!("Received NAK for start frame, resending it");
+                        (dev.write_all(&buff))?;
                     } else {
                         warn!("Expected ACK, got {}", c);
                     }
@@ -386,6 +787,9 @@ impl Ymodem {
                     "Exhausted max retries ({}) while sending start frame in YMODEM transfer",
                     self.max_errors
                 );
+                if let Err(err) = send_cancel(dev, self.can_count) {
+                    warn!("Error sending CAN bytes: {}", err);
+                }
                 return Err(Error::ExhaustedRetries);
             }
         }
@@ -410,11 +814,14 @@ impl Ymodem {
                     "Exhausted max retries ({}) while sending start frame in YMODEM transfer",
                     self.max_errors
                 );
+                if let Err(err) = send_cancel(dev, self.can_count) {
+                    warn!("Error sending CAN bytes: {}", err);
+                }
                 return Err(Error::ExhaustedRetries);
             }
         }
 
-        return Ok(());
+        Ok(())
     }
 
     fn send_stream<D: Read + Write, R: Read>(
@@ -431,61 +838,84 @@ impl Ymodem {
             } else {
                 1024
             };
-            let mut buff = vec![self.pad_byte; packet_size as usize + 3];
-            let n = (stream.read(&mut buff[3..]))?;
+            let mut data = vec![self.pad_byte; packet_size];
+            let n = (fill_buf(stream, &mut data))?;
             if n == 0 {
                 dbg!("Reached EOF");
                 return Ok(());
             }
 
             block_num += 1;
-            if packet_size == 128 {
-                buff[0] = SOH;
+            let packet = PacketBuilder {
+                // Only the low byte is sent, so block numbers wrap from 255 to 0
+                block_num: (block_num & 0xFF) as u8,
+                checksum: Checksum::CRC16,
+                pad_byte: self.pad_byte,
+            };
+            let buff = if packet_size == 128 {
+                packet.build_128(&data[..n])
             } else {
-                buff[0] = STX;
-            }
-            buff[1] = (block_num & 0xFF) as u8;
-            buff[2] = 0xFF - buff[1];
-
-            let crc = calc_crc(&buff[3..]);
-            buff.push(((crc >> 8) & 0xFF) as u8);
-            buff.push((crc & 0xFF) as u8);
-
-            println!("Sending block {}", block_num);
-            (dev.write_all(&buff))?;
+                packet.build_1024(&data[..n])
+            };
 
-            match (get_byte_timeout(dev))? {
-                Some(c) => {
-                    if c == ACK {
-                        dbg!("Received ACK for block {}", block_num);
-                        continue;
-                    } else {
-                        warn!("Expected ACK, got {}", c);
+            // The block is kept until it's acknowledged, so a NAK resends it
+            // without reading from the stream again
+            loop {
+                debug!("Sending block {}", block_num);
+                (dev.write_all(&buff))?;
+
+                match (get_byte_timeout(dev))? {
+                    Some(c) => {
+                        if c == ACK {
+                            dbg!("Received ACK for block {}", block_num);
+                            trace_event!(
+                                block = block_num,
+                                of = packets_to_send,
+                                "block acknowledged"
+                            );
+                            if self.reset_errors_on_success {
+                                self.errors = 0;
+                            }
+                            break;
+                        } else {
+                            warn!("Expected ACK, got {}", c);
+                        }
+                        // TODO handle CAN bytes
                     }
-                    // TODO handle CAN bytes
+                    None => warn!("Timeout waiting for ACK for block {}", block_num),
                 }
-                None => warn!("Timeout waiting for ACK for block {}", block_num),
-            }
+                trace_event!(
+                    block = block_num,
+                    errors = self.errors + 1,
+                    "block not acknowledged"
+                );
 
-            self.errors += 1;
+                self.errors += 1;
 
-            if self.errors >= self.max_errors {
-                eprint!(
-                    "Exhausted max retries ({}) while sending block {} in YMODEM transfer",
-                    self.max_errors, block_num
-                );
-                return Err(Error::ExhaustedRetries);
+                if self.errors >= self.max_errors {
+                    eprint!(
+                        "Exhausted max retries ({}) while sending block {} in YMODEM transfer",
+                        self.max_errors, block_num
+                    );
+                    if let Err(err) = send_cancel(dev, self.can_count) {
+                        warn!("Error sending CAN bytes: {}", err);
+                    }
+                    return Err(Error::ExhaustedRetries);
+                }
             }
         }
     }
 
-    fn finish_send<D: Read + Write>(&mut self, dev: &mut D) -> Result<()> {
+    /// Sends the EOT sequence for a file. In `batch_mode`, also waits for the
+    /// receiver to request the next file with a `C`.
+    fn finish_send<D: Read + Write>(&mut self, dev: &mut D, batch_mode: bool) -> Result<()> {
         loop {
             (dev.write_all(&[EOT]))?;
 
             match (get_byte_timeout(dev))? {
                 Some(c) => {
                     if c == NAK {
+                        trace_event!("first EOT answered with NAK");
                         break;
                     } else {
                         log::warn!("Expected ACK, got {}", c);
@@ -512,6 +942,7 @@ impl Ymodem {
                 Some(c) => {
                     if c == ACK {
                         info!("YMODEM transmission successful");
+                        trace_event!("second EOT acknowledged");
                         break;
                     } else {
                         log::warn!("Expected ACK, got {}", c);
@@ -531,44 +962,70 @@ impl Ymodem {
             }
         }
 
-        loop {
-            match (get_byte_timeout(dev))? {
-                Some(c) => {
-                    if c == CRC {
-                        info!("YMODEM transmission successful");
-                        break;
-                    } else {
-                        log::warn!("Expected ACK, got {}", c);
+        if batch_mode {
+            loop {
+                match (get_byte_timeout(dev))? {
+                    Some(c) => {
+                        if c == CRC {
+                            debug!("Receiver is ready for the next file");
+                            break;
+                        } else {
+                            log::warn!("Expected C, got {}", c);
+                        }
                     }
+                    None => warn!("Timeout waiting for C for the next file"),
                 }
-                None => warn!("Timeout waiting for ACK for EOT"),
-            }
 
-            self.errors += 1;
+                self.errors += 1;
 
-            if self.errors >= self.max_errors {
-                eprint!(
-                    "Exhausted max retries ({}) while waiting for ACK for EOT",
-                    self.max_errors
-                );
-                return Err(Error::ExhaustedRetries);
+                if self.errors >= self.max_errors {
+                    eprint!(
+                        "Exhausted max retries ({}) while waiting for C for the next file",
+                        self.max_errors
+                    );
+                    return Err(Error::ExhaustedRetries);
+                }
             }
         }
 
-        self.send_end_frame(dev)?;
-
         Ok(())
     }
 
-    fn send_end_frame<D: Read + Write>(&mut self, dev: &mut D) -> Result<()> {
-        let mut buff = vec![0x00; 128 as usize + 3];
-        buff[0] = SOH;
-        buff[1] = 0x00;
-        buff[2] = 0xFF;
+    fn send_end_frame<D: Read + Write>(&mut self, dev: &mut D, wait_for_c: bool) -> Result<()> {
+        // A header with an empty file name ends the batch
+        let buff = PacketBuilder {
+            block_num: 0,
+            checksum: Checksum::CRC16,
+            pad_byte: 0x00,
+        }
+        .build_128(&[]);
 
-        let crc = calc_crc(&buff[3..]);
-        buff.push(((crc >> 8) & 0xFF) as u8);
-        buff.push((crc & 0xFF) as u8);
+        // The receiver requests the next header with a C after ACKing the last EOT.
+        // Without any files, the C that started the session is that request.
+        if wait_for_c {
+            loop {
+                match (get_byte_timeout(dev))? {
+                    Some(c) => {
+                        if c == CRC {
+                            debug!("Received C for end frame");
+                            break;
+                        } else {
+                            warn!("Expected C, got {}", c);
+                        }
+                    }
+                    None => warn!("Timeout waiting for C for end frame"),
+                }
+
+                self.errors += 1;
+                if self.errors >= self.max_errors {
+                    eprint!(
+                        "Exhausted max retries ({}) while waiting to send end frame in YMODEM transfer",
+                        self.max_errors
+                    );
+                    return Err(Error::ExhaustedRetries);
+                }
+            }
+        }
 
         (dev.write_all(&buff))?;
 
@@ -576,26 +1033,30 @@ impl Ymodem {
             match (get_byte_timeout(dev))? {
                 Some(c) => {
                     if c == ACK {
-                        dbg!("Received ACK for start frame");
+                        debug!("Received ACK for end frame");
+                        self.session_complete = true;
                         break;
+                    } else if c == NAK {
+                        warn!("Received NAK for end frame, resending it");
+                        (dev.write_all(&buff))?;
                     } else {
                         warn!("Expected ACK, got {}", c);
                     }
                     // TODO handle CAN bytes
                 }
-                None => warn!("Timeout waiting for ACK for start frame"),
+                None => warn!("Timeout waiting for ACK for end frame"),
             }
 
             self.errors += 1;
             if self.errors >= self.max_errors {
                 eprint!(
-                    "Exhausted max retries ({}) while sending start frame in YMODEM transfer",
+                    "Exhausted max retries ({}) while sending end frame in YMODEM transfer",
                     self.max_errors
                 );
                 return Err(Error::ExhaustedRetries);
             }
         }
 
-        return Ok(());
+        Ok(())
     }
 }
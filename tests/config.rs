@@ -0,0 +1,30 @@
+//! Validation of the transfer configurations
+extern crate ymodem;
+
+use ymodem::xmodem::Xmodem;
+use ymodem::ymodem::Ymodem;
+
+#[test]
+fn default_configs_are_valid() {
+    assert_eq!(Xmodem::new().validate(), Ok(()));
+    assert_eq!(Ymodem::new().validate(), Ok(()));
+}
+
+#[test]
+fn zero_limits_are_invalid() {
+    let mut xmodem = Xmodem::new();
+    xmodem.max_errors = 0;
+    assert!(xmodem.validate().is_err());
+
+    let mut xmodem = Xmodem::new();
+    xmodem.max_initial_errors = 0;
+    assert!(xmodem.validate().is_err());
+
+    let mut ymodem = Ymodem::new();
+    ymodem.max_errors = 0;
+    assert!(ymodem.validate().is_err());
+
+    let mut ymodem = Ymodem::new();
+    ymodem.can_count = 0;
+    assert!(ymodem.validate().is_err());
+}
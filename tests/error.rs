@@ -0,0 +1,30 @@
+//! Formatting of transfer errors
+extern crate ymodem;
+
+use std::error::Error as StdError;
+use std::io::{self, ErrorKind};
+use ymodem::Error;
+
+#[test]
+fn display_io_error_includes_kind() {
+    let err = Error::from(io::Error::new(ErrorKind::TimedOut, "timed out"));
+    assert_eq!(err.to_string(), "I/O error (TimedOut): timed out");
+    assert!(err.source().is_some());
+}
+
+#[test]
+fn display_protocol_errors() {
+    assert_eq!(
+        Error::ExhaustedRetries.to_string(),
+        "Transfer retries exhausted"
+    );
+    assert_eq!(
+        Error::Canceled.to_string(),
+        "Transfer canceled by the other end"
+    );
+    assert_eq!(
+        Error::ParseError("bad size".to_string()).to_string(),
+        "Invalid header: bad size"
+    );
+    assert!(Error::Canceled.source().is_none());
+}
@@ -40,21 +40,18 @@ fn xmodem_recv(checksum_mode: Checksum, block_length: BlockLength, data_len: usi
 
     let mut send_builder = Command::new("sb");
     send_builder.arg("--xmodem");
-    match block_length {
-        BlockLength::OneK => {
-            send_builder.arg("--1k");
-        }
-        _ => {}
+    if let BlockLength::OneK = block_length {
+        send_builder.arg("--1k");
     }
-    let send = send_builder
+    let mut send = send_builder
         .arg(send_file.path())
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::null())
         .spawn()
         .unwrap();
-    let tx_stream = send.stdin.unwrap();
-    let rx_stream = send.stdout.unwrap();
+    let tx_stream = send.stdin.take().unwrap();
+    let rx_stream = send.stdout.take().unwrap();
     let mut serial_dev = ChildStdInOut {
         stdin: tx_stream,
         stdout: rx_stream,
@@ -65,14 +62,14 @@ fn xmodem_recv(checksum_mode: Checksum, block_length: BlockLength, data_len: usi
     xmodem
         .recv(&mut serial_dev, &mut recv_data, checksum_mode)
         .unwrap();
+    drop(serial_dev);
+    send.wait().unwrap();
 
     let mut sent_data = Vec::new();
     send_file.seek(std::io::SeekFrom::Start(0)).unwrap();
     send_file.read_to_end(&mut sent_data).unwrap();
     let mut padded_data = sent_data.clone();
-    for _ in 0..(128 - sent_data.len() % 128) {
-        padded_data.push(0x1a);
-    }
+    padded_data.resize(sent_data.len() + 128 - sent_data.len() % 128, 0x1a);
     assert_eq!(padded_data, recv_data);
 }
 
@@ -103,7 +100,7 @@ fn xmodem_send_standard() {
     thread_rng().fill_bytes(&mut data);
 
     let mut recv_file = NamedTempFile::new().unwrap();
-    let recv = Command::new("rb")
+    let mut recv = Command::new("rb")
         .arg("--xmodem")
         .arg(recv_file.path())
         .stdin(Stdio::piped())
@@ -112,8 +109,8 @@ fn xmodem_send_standard() {
         .spawn()
         .unwrap();
 
-    let tx_stream = recv.stdin.unwrap();
-    let rx_stream = recv.stdout.unwrap();
+    let tx_stream = recv.stdin.take().unwrap();
+    let rx_stream = recv.stdout.take().unwrap();
     let mut serial_dev = ChildStdInOut {
         stdin: tx_stream,
         stdout: rx_stream,
@@ -121,13 +118,13 @@ fn xmodem_send_standard() {
 
     let mut xmodem = Xmodem::new();
     xmodem.send(&mut serial_dev, &mut &data[..]).unwrap();
+    drop(serial_dev);
+    recv.wait().unwrap();
 
     let mut received_data = Vec::new();
     recv_file.read_to_end(&mut received_data).unwrap();
     let mut padded_data = data.clone();
-    for _ in 0..(128 - data.len() % 128) {
-        padded_data.push(0x1a);
-    }
+    padded_data.resize(data.len() + 128 - data.len() % 128, 0x1a);
     assert_eq!(received_data, padded_data);
 }
 
@@ -138,7 +135,7 @@ fn xmodem_send_crc() {
     thread_rng().fill_bytes(&mut data);
 
     let mut recv_file = NamedTempFile::new().unwrap();
-    let recv = Command::new("rb")
+    let mut recv = Command::new("rb")
         .arg("--xmodem")
         .arg("--with-crc")
         .arg(recv_file.path())
@@ -148,8 +145,8 @@ fn xmodem_send_crc() {
         .spawn()
         .unwrap();
 
-    let tx_stream = recv.stdin.unwrap();
-    let rx_stream = recv.stdout.unwrap();
+    let tx_stream = recv.stdin.take().unwrap();
+    let rx_stream = recv.stdout.take().unwrap();
     let mut serial_dev = ChildStdInOut {
         stdin: tx_stream,
         stdout: rx_stream,
@@ -157,12 +154,12 @@ fn xmodem_send_crc() {
 
     let mut xmodem = Xmodem::new();
     xmodem.send(&mut serial_dev, &mut &data[..]).unwrap();
+    drop(serial_dev);
+    recv.wait().unwrap();
 
     let mut received_data = Vec::new();
     recv_file.read_to_end(&mut received_data).unwrap();
     let mut padded_data = data.clone();
-    for _ in 0..(128 - data.len() % 128) {
-        padded_data.push(0x1a);
-    }
+    padded_data.resize(data.len() + 128 - data.len() % 128, 0x1a);
     assert_eq!(received_data, padded_data);
 }
@@ -5,7 +5,8 @@ extern crate ymodem;
 
 use std::io::{self, ErrorKind, Read, Write};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use ymodem::xmodem::{BlockLength, Checksum, Xmodem};
+use ymodem::xmodem::{BlockLength, Checksum, PacketBuilder, Xmodem};
+use ymodem::ymodem::{Error, RecvMetadata, Ymodem};
 
 struct BidirectionalPipe {
     pin: Receiver<u8>,
@@ -14,8 +15,8 @@ struct BidirectionalPipe {
 
 impl Read for BidirectionalPipe {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        for idx in 0..buf.len() {
-            buf[idx] = match self.pin.recv() {
+        for byte in buf.iter_mut() {
+            *byte = match self.pin.recv() {
                 Ok(v) => v,
                 Err(e) => return Err(std::io::Error::new(ErrorKind::BrokenPipe, e)),
             }
@@ -50,8 +51,8 @@ fn loopback() -> (BidirectionalPipe, BidirectionalPipe) {
 fn xmodem_loopback(checksum_mode: Checksum, block_length: BlockLength, data_len: usize) {
     let mut data_out = vec![0; data_len];
     // We don't really need the rng here
-    for idx in 0..data_len {
-        data_out[idx] = ((idx + 7) * 13) as u8;
+    for (idx, byte) in data_out.iter_mut().enumerate() {
+        *byte = ((idx + 7) * 13) as u8;
     }
     let (mut p1, mut p2) = loopback();
     let handle = std::thread::spawn(move || {
@@ -68,10 +69,8 @@ fn xmodem_loopback(checksum_mode: Checksum, block_length: BlockLength, data_len:
     });
     let mut dato = handle.join().unwrap();
     // Pad output data to multiple of block length for comparison
-    let bl = block_length as usize;
-    for _ in 0..(bl - data_len % bl) {
-        dato.push(0x1a);
-    }
+    let bl = block_length.size();
+    dato.resize(data_len + bl - data_len % bl, 0x1a);
     let dati = handle2.join().unwrap();
     assert_eq!(dato.len(), dati.len());
     assert_eq!(dato, dati);
@@ -97,3 +96,1166 @@ fn xmodem_loopback_long_crc() {
     // make sure we wrap block counter
     xmodem_loopback(Checksum::CRC16, BlockLength::Standard, 50000);
 }
+
+#[test]
+fn xmodem_loopback_onek_g() {
+    let data_out: Vec<u8> = (0..5000).map(|idx| ((idx + 7) * 13) as u8).collect();
+    let expected = data_out.clone();
+    let (mut p1, mut p2) = loopback();
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.send(&mut p1, &mut &data_out[..]).unwrap();
+    });
+    let mut xmodem = Xmodem::new();
+    xmodem.block_length = BlockLength::OneKG;
+    let mut data_in = Vec::new();
+    xmodem
+        .recv(&mut p2, &mut data_in, Checksum::Standard)
+        .unwrap();
+    handle.join().unwrap();
+
+    assert_eq!(data_in.len(), 5120);
+    assert_eq!(&data_in[..5000], &expected[..]);
+    assert!(data_in[5000..].iter().all(|&b| b == 0x1a));
+}
+
+/// Builds a YMODEM block with a CRC16, using STX for 1024-byte blocks.
+fn ymodem_block(block_num: u8, data: &[u8]) -> Vec<u8> {
+    let packet = PacketBuilder::new(block_num, Checksum::CRC16);
+    if data.len() == 1024 {
+        packet.build_1024(data)
+    } else {
+        packet.build_128(data)
+    }
+}
+
+/// Builds a YMODEM header (block 0) with the given raw file name and size fields.
+fn ymodem_header(file_name: &[u8], file_size: &[u8]) -> Vec<u8> {
+    let mut data = vec![0; 128];
+    data[..file_name.len()].copy_from_slice(file_name);
+    let size_start = file_name.len() + 1;
+    data[size_start..size_start + file_size.len()].copy_from_slice(file_size);
+    ymodem_block(0, &data)
+}
+
+fn ymodem_recv_scripted(script: &[u8]) -> ymodem::ymodem::Result<(RecvMetadata, Vec<u8>)> {
+    let (mut p1, mut p2) = loopback();
+    p1.write_all(script).unwrap();
+    let mut ymodem = Ymodem::new();
+    let mut data_in = Vec::new();
+    ymodem
+        .recv(&mut p2, &mut data_in)
+        .map(|metadata| (metadata, data_in))
+}
+
+#[test]
+fn ymodem_recv_invalid_file_name() {
+    match ymodem_recv_scripted(&ymodem_header(&[0xFF, 0xFE], b"10")) {
+        Err(Error::ParseError(_)) => {}
+        other => panic!("expected ParseError, got {:?}", other),
+    }
+}
+
+#[test]
+fn ymodem_recv_invalid_file_size() {
+    match ymodem_recv_scripted(&ymodem_header(b"foo.bin", b"abc")) {
+        Err(Error::ParseError(msg)) => assert!(msg.contains("abc")),
+        other => panic!("expected ParseError, got {:?}", other),
+    }
+}
+
+#[test]
+fn ymodem_recv_cancels_with_can_sequence() {
+    let (mut p1, mut p2) = loopback();
+    // A header must be block 0
+    let mut header = ymodem_header(b"foo.bin", b"10");
+    header[1] = 0x01;
+    header[2] = 0xFE;
+    p1.write_all(&header).unwrap();
+
+    let mut ymodem = Ymodem::new();
+    match ymodem.recv(&mut p2, &mut Vec::new()) {
+        Err(Error::Canceled) => {}
+        other => panic!("expected Canceled, got {:?}", other),
+    }
+
+    // 'C', then the cancel sequence
+    let mut reply = [0; 17];
+    p1.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[0], 0x43);
+    assert_eq!(&reply[1..9], &[0x18; 8]);
+    assert_eq!(&reply[9..], &[0x08; 8]);
+}
+
+/// Builds a 128-byte XMODEM block with a standard checksum.
+fn xmodem_block(block_num: u8, data: &[u8], corrupt: bool) -> Vec<u8> {
+    let mut packet = PacketBuilder::new(block_num, Checksum::Standard).build_128(data);
+    if corrupt {
+        *packet.last_mut().unwrap() ^= 0xFF;
+    }
+    packet
+}
+
+fn xmodem_recv_noisy(reset_errors_on_success: bool) -> ymodem::xmodem::Result<Vec<u8>> {
+    let (mut p1, mut p2) = loopback();
+    let data = [0x55; 128];
+    for block_num in 1..3 {
+        p1.write_all(&xmodem_block(block_num, &data, true)).unwrap();
+        p1.write_all(&xmodem_block(block_num, &data, false))
+            .unwrap();
+    }
+    p1.write_all(&[0x04]).unwrap();
+
+    let mut xmodem = Xmodem::new();
+    xmodem.max_errors = 2;
+    xmodem.reset_errors_on_success = reset_errors_on_success;
+    let mut data_in = Vec::new();
+    xmodem
+        .recv(&mut p2, &mut data_in, Checksum::Standard)
+        .map(|_| data_in)
+}
+
+#[test]
+fn xmodem_recv_exhausts_scattered_errors() {
+    match xmodem_recv_noisy(false) {
+        Err(Error::ExhaustedRetries) => {}
+        other => panic!("expected ExhaustedRetries, got {:?}", other),
+    }
+}
+
+#[test]
+fn xmodem_recv_reset_errors_on_success() {
+    assert_eq!(xmodem_recv_noisy(true).unwrap(), vec![0x55; 256]);
+}
+
+#[test]
+fn xmodem_recv_detect_checksum_mode() {
+    let (mut p1, mut p2) = loopback();
+    for block_num in 1..3 {
+        p1.write_all(&PacketBuilder::new(block_num, Checksum::CRC16).build_128(&[0x55; 128]))
+            .unwrap();
+    }
+    p1.write_all(&[0x04]).unwrap();
+
+    let mut xmodem = Xmodem::new();
+    xmodem.detect_checksum_mode = true;
+    let mut data_in = Vec::new();
+    xmodem
+        .recv(&mut p2, &mut data_in, Checksum::Standard)
+        .unwrap();
+    assert_eq!(data_in, vec![0x55; 256]);
+    assert_eq!(xmodem.errors(), 0);
+}
+
+#[test]
+fn xmodem_recv_detect_checksum_mode_corrupt_block() {
+    // A corrupt standard checksum must not be mistaken for the start of a CRC
+    let mut script = xmodem_block(1, &[0x55; 128], true);
+    script.extend(xmodem_block(1, &[0x55; 128], false));
+    script.push(0x04);
+    let mut dev = Scripted {
+        input: &script,
+        output: Vec::new(),
+    };
+
+    let mut xmodem = Xmodem::new();
+    xmodem.detect_checksum_mode = true;
+    let mut data_in = Vec::new();
+    xmodem
+        .recv(&mut dev, &mut data_in, Checksum::Standard)
+        .unwrap();
+    assert_eq!(data_in, vec![0x55; 128]);
+    assert_eq!(xmodem.errors(), 1);
+    assert_eq!(dev.output, [0x15, 0x15, 0x06, 0x06]);
+}
+
+#[test]
+fn ymodem_recv_session() {
+    let (mut p1, mut p2) = loopback();
+    p1.write_all(&ymodem_header(b"foo.bin", b"1100 14567716601 100644 7"))
+        .unwrap();
+    p1.write_all(&ymodem_block(1, &[0x55; 1024])).unwrap();
+    p1.write_all(&ymodem_block(2, &[0x66; 128])).unwrap();
+    p1.write_all(&[0x04, 0x04]).unwrap();
+    p1.write_all(&ymodem_header(b"", b"")).unwrap();
+
+    let mut ymodem = Ymodem::new();
+    let mut data_in = Vec::new();
+    let metadata = ymodem.recv(&mut p2, &mut data_in).unwrap();
+    assert_eq!(
+        metadata,
+        RecvMetadata {
+            file_name: "foo.bin".to_string(),
+            file_size: 1100,
+            mtime: Some(0o14567716601),
+            file_mode: Some(0o100644),
+            serial_number: Some(7),
+        }
+    );
+    assert_eq!(&data_in[..1024], &[0x55; 1024][..]);
+    assert_eq!(&data_in[1024..], &[0x66; 76][..]);
+
+    // 'C', ACK + 'C' for the header, two data ACKs, NAK for the first EOT,
+    // ACK + 'C' for the second EOT, then ACK for the end frame
+    let mut reply = [0; 9];
+    p1.read_exact(&mut reply).unwrap();
+    assert_eq!(
+        reply,
+        [0x43, 0x06, 0x43, 0x06, 0x06, 0x15, 0x06, 0x43, 0x06]
+    );
+}
+
+#[test]
+fn xmodem_abort() {
+    let (mut p1, mut p2) = loopback();
+    let mut xmodem = Xmodem::new();
+    xmodem.can_count = 5;
+    xmodem.abort(&mut p2).unwrap();
+
+    let mut sent = [0; 10];
+    p1.read_exact(&mut sent).unwrap();
+    assert_eq!(
+        sent,
+        [0x18, 0x18, 0x18, 0x18, 0x18, 0x08, 0x08, 0x08, 0x08, 0x08]
+    );
+}
+
+#[test]
+fn ymodem_send_cancels_unacknowledged_start_frame() {
+    let (mut p1, mut p2) = loopback();
+    p1.write_all(&[0x43, 0x00, 0x00]).unwrap();
+
+    let mut ymodem = Ymodem::new();
+    ymodem.max_errors = 2;
+    match ymodem.send(&mut p2, &mut &[0x55; 10][..], "foo.bin", 10) {
+        Err(Error::ExhaustedRetries) => {}
+        other => panic!("expected ExhaustedRetries, got {:?}", other),
+    }
+
+    let mut sent = [0; 133 + 16];
+    p1.read_exact(&mut sent).unwrap();
+    assert_eq!(&sent[133..141], &[0x18; 8]);
+    assert_eq!(&sent[141..], &[0x08; 8]);
+}
+
+#[test]
+fn xmodem_send_canceled_before_max_errors() {
+    let (mut p1, mut p2) = loopback();
+    p1.write_all(&[0x18, 0x18]).unwrap();
+
+    let mut xmodem = Xmodem::new();
+    xmodem.max_errors = 1;
+    match xmodem.send(&mut p2, &mut &[0x55; 10][..]) {
+        Err(Error::Canceled) => {}
+        other => panic!("expected Canceled, got {:?}", other),
+    }
+    assert_eq!(xmodem.errors(), 0);
+}
+
+#[test]
+fn ymodem_recv_short_file() {
+    let mut script = ymodem_header(b"foo.bin", b"1100");
+    script.extend(ymodem_block(1, &[0x55; 1024]));
+    script.extend(&[0x04, 0x04]);
+    script.extend(ymodem_header(b"", b""));
+    match ymodem_recv_scripted(&script) {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), ErrorKind::UnexpectedEof),
+        other => panic!("expected UnexpectedEof error, got {:?}", other),
+    }
+}
+
+#[test]
+fn xmodem_recv_block_num_wraparound() {
+    let (mut p1, mut p2) = loopback();
+    // Block numbers go 1..=255, 0, 1, ... so 300 blocks wrap once
+    for block in 1..=300usize {
+        p1.write_all(&xmodem_block(block as u8, &[block as u8; 128], false))
+            .unwrap();
+    }
+    p1.write_all(&[0x04]).unwrap();
+
+    let mut xmodem = Xmodem::new();
+    let mut data_in = Vec::new();
+    xmodem
+        .recv(&mut p2, &mut data_in, Checksum::Standard)
+        .unwrap();
+    assert_eq!(data_in.len(), 300 * 128);
+    for (idx, chunk) in data_in.chunks(128).enumerate() {
+        assert!(chunk.iter().all(|&b| b == (idx + 1) as u8));
+    }
+    assert_eq!(xmodem.errors(), 0);
+}
+
+#[test]
+fn ymodem_recv_retries_corrupt_header() {
+    let mut corrupt = ymodem_header(b"foo.bin", b"10");
+    corrupt[5] ^= 0xFF;
+    let mut script = corrupt;
+    script.extend(ymodem_header(b"bar.bin", b"10"));
+    script.extend(ymodem_block(1, &[0x55; 128]));
+    script.extend(&[0x04, 0x04]);
+    script.extend(ymodem_header(b"", b""));
+
+    let (metadata, data_in) = ymodem_recv_scripted(&script).unwrap();
+    assert_eq!(metadata.file_name, "bar.bin");
+    assert_eq!(metadata.file_size, 10);
+    assert_eq!(data_in, vec![0x55; 10]);
+}
+
+#[test]
+fn ymodem_recv_ignore_non_digits_keeps_optional_fields() {
+    let (mut p1, mut p2) = loopback();
+    p1.write_all(&ymodem_header(b"foo.bin", b"10V 14567716601"))
+        .unwrap();
+    p1.write_all(&ymodem_block(1, &[0x55; 128])).unwrap();
+    p1.write_all(&[0x04, 0x04]).unwrap();
+    p1.write_all(&ymodem_header(b"", b"")).unwrap();
+
+    let mut ymodem = Ymodem::new();
+    ymodem.ignore_non_digits_on_file_size = true;
+    let metadata = ymodem.recv(&mut p2, &mut Vec::new()).unwrap();
+    assert_eq!(metadata.file_size, 10);
+    assert_eq!(metadata.mtime, Some(0o14567716601));
+}
+
+/// A reader that returns at most one byte per `read` call.
+struct Trickle<'a>(&'a [u8]);
+
+impl<'a> Read for Trickle<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.0.is_empty() || buf.is_empty() {
+            return Ok(0);
+        }
+        buf[0] = self.0[0];
+        self.0 = &self.0[1..];
+        Ok(1)
+    }
+}
+
+#[test]
+fn xmodem_loopback_short_reads() {
+    let data_out: Vec<u8> = (0..300).map(|idx| idx as u8).collect();
+    let expected = data_out.clone();
+    let (mut p1, mut p2) = loopback();
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.send(&mut p1, &mut Trickle(&data_out)).unwrap();
+    });
+    let mut xmodem = Xmodem::new();
+    let mut data_in = Vec::new();
+    xmodem
+        .recv(&mut p2, &mut data_in, Checksum::Standard)
+        .unwrap();
+    handle.join().unwrap();
+
+    assert_eq!(data_in.len(), 384);
+    assert_eq!(&data_in[..300], &expected[..]);
+}
+
+#[test]
+fn ymodem_loopback_standard() {
+    ymodem_loopback(3000);
+}
+
+#[test]
+fn xmodem_loopback_block_num_wraparound() {
+    // 300 blocks, so the block number wraps from 255 to 0
+    xmodem_loopback(Checksum::Standard, BlockLength::Standard, 300 * 128 - 10);
+}
+
+#[test]
+fn ymodem_loopback_block_num_wraparound() {
+    // 300 blocks, so the block number wraps from 255 to 0
+    ymodem_loopback(300 * 1024 - 10);
+}
+
+fn ymodem_loopback(data_len: usize) {
+    let data_out: Vec<u8> = (0..data_len).map(|idx| ((idx + 7) * 13) as u8).collect();
+    let expected = data_out.clone();
+    let (mut p1, mut p2) = loopback();
+    let handle = std::thread::spawn(move || {
+        let mut ymodem = Ymodem::new();
+        ymodem
+            .send(&mut p1, &mut &data_out[..], "foo.bin", data_len as u64)
+            .unwrap();
+        ymodem
+    });
+    let mut ymodem = Ymodem::new();
+    let mut data_in = Vec::new();
+    let metadata = ymodem.recv(&mut p2, &mut data_in).unwrap();
+    let sender = handle.join().unwrap();
+
+    assert_eq!(sender.errors(), 0);
+    assert_eq!(ymodem.errors(), 0);
+    assert_eq!(metadata.file_name, "foo.bin");
+    assert_eq!(metadata.file_size, data_len as u64);
+    assert_eq!(data_in, expected);
+}
+
+#[test]
+fn ymodem_recv_retries_bad_block_number() {
+    let mut script = ymodem_header(b"foo.bin", b"10");
+    // Block 2 where block 1 is expected, then the right block
+    script.extend(ymodem_block(2, &[0x66; 128]));
+    script.extend(ymodem_block(1, &[0x55; 128]));
+    script.extend(&[0x04, 0x04]);
+    script.extend(ymodem_header(b"", b""));
+
+    let (_, data_in) = ymodem_recv_scripted(&script).unwrap();
+    assert_eq!(data_in, vec![0x55; 10]);
+}
+
+#[test]
+fn xmodem_send_cancels_after_max_errors() {
+    let (mut p1, mut p2) = loopback();
+    // Request CRC mode, then NAK the first block
+    p1.write_all(&[0x43, 0x15]).unwrap();
+
+    let mut xmodem = Xmodem::new();
+    xmodem.max_errors = 1;
+    xmodem.can_count = 2;
+    match xmodem.send(&mut p2, &mut &[0x55; 10][..]) {
+        Err(Error::ExhaustedRetries) => {}
+        other => panic!("expected ExhaustedRetries, got {:?}", other),
+    }
+
+    let mut sent = [0; 133 + 4];
+    p1.read_exact(&mut sent).unwrap();
+    assert_eq!(&sent[133..], &[0x18, 0x18, 0x08, 0x08]);
+}
+
+#[test]
+fn ymodem_send_end_frame_waits_for_c() {
+    let (mut p1, mut p2) = loopback();
+    // 'C' to start, ACK and 'C' for the header, ACK for the block,
+    // NAK and ACK for the EOTs, but never a 'C' for the end frame
+    p1.write_all(&[0x43, 0x06, 0x43, 0x06, 0x15, 0x06, 0x00, 0x00])
+        .unwrap();
+
+    let mut ymodem = Ymodem::new();
+    ymodem.max_errors = 2;
+    match ymodem.send(&mut p2, &mut &[0x55; 10][..], "foo.bin", 10) {
+        Err(Error::ExhaustedRetries) => {}
+        other => panic!("expected ExhaustedRetries, got {:?}", other),
+    }
+    assert!(!ymodem.session_complete());
+    drop(p2);
+
+    // The header, the block and the two EOTs, without an end frame
+    let mut sent = [0; 133 + 133 + 2];
+    p1.read_exact(&mut sent).unwrap();
+    assert_eq!(&sent[266..], &[0x04, 0x04]);
+    assert!(p1.read(&mut [0]).is_err());
+}
+
+#[test]
+fn ymodem_recv_ignore_non_digits_only_filters_size_field() {
+    let (mut p1, mut p2) = loopback();
+    p1.write_all(&ymodem_header(b"foo.bin", b"2048 1234567"))
+        .unwrap();
+    p1.write_all(&ymodem_block(1, &[0x55; 1024])).unwrap();
+    p1.write_all(&ymodem_block(2, &[0x66; 1024])).unwrap();
+    p1.write_all(&[0x04, 0x04]).unwrap();
+    p1.write_all(&ymodem_header(b"", b"")).unwrap();
+
+    let mut ymodem = Ymodem::new();
+    ymodem.ignore_non_digits_on_file_size = true;
+    let mut data_in = Vec::new();
+    let metadata = ymodem.recv(&mut p2, &mut data_in).unwrap();
+    // The digits of the mtime must not be glued onto the size
+    assert_eq!(metadata.file_size, 2048);
+    assert_eq!(metadata.mtime, Some(0o1234567));
+    assert_eq!(data_in.len(), 2048);
+}
+
+#[test]
+fn xmodem_recv_strip_padding() {
+    // None of these bytes are 0x1A, so only the padding is stripped
+    let data_out: Vec<u8> = (0..2200).map(|idx| (idx % 26) as u8).collect();
+    let expected = data_out.clone();
+    let (mut p1, mut p2) = loopback();
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.block_length = BlockLength::OneK;
+        xmodem.send(&mut p1, &mut &data_out[..]).unwrap();
+    });
+    let mut xmodem = Xmodem::new();
+    xmodem.pad_byte = 0x1a;
+    xmodem.strip_padding = true;
+    let mut data_in = Vec::new();
+    xmodem.recv(&mut p2, &mut data_in, Checksum::CRC16).unwrap();
+    handle.join().unwrap();
+
+    assert_eq!(data_in, expected);
+}
+
+#[test]
+fn ymodem_recv_strip_path_from_filename() {
+    for name in &[
+        &b"firmware/app.bin"[..],
+        b"C:\\firmware\\app.bin",
+        b"app.bin",
+    ] {
+        let (mut p1, mut p2) = loopback();
+        p1.write_all(&ymodem_header(name, b"10")).unwrap();
+        p1.write_all(&ymodem_block(1, &[0x55; 128])).unwrap();
+        p1.write_all(&[0x04, 0x04]).unwrap();
+        p1.write_all(&ymodem_header(b"", b"")).unwrap();
+
+        let mut ymodem = Ymodem::new();
+        ymodem.strip_path_from_filename = true;
+        let metadata = ymodem.recv(&mut p2, &mut Vec::new()).unwrap();
+        assert_eq!(metadata.file_name, "app.bin");
+    }
+}
+
+#[test]
+fn xmodem_send_resets_errors_after_start() {
+    let (mut p1, mut p2) = loopback();
+    // Three bad bytes before the NAK that starts the transfer, then one NAK
+    // during the data phase
+    p1.write_all(&[0x00, 0x00, 0x00, 0x15, 0x15, 0x06, 0x06, 0x06])
+        .unwrap();
+
+    let mut xmodem = Xmodem::new();
+    xmodem.max_errors = 4;
+    xmodem.send(&mut p2, &mut &[0x55; 256][..]).unwrap();
+    assert_eq!(xmodem.errors(), 1);
+    assert_eq!(xmodem.initial_errors(), 3);
+}
+
+#[test]
+fn xmodem_send_start_exhausts_initial_errors() {
+    let (mut p1, mut p2) = loopback();
+    p1.write_all(&[0x00, 0x00, 0x00]).unwrap();
+
+    let mut xmodem = Xmodem::new();
+    xmodem.max_initial_errors = 2;
+    xmodem.can_count = 2;
+    match xmodem.send(&mut p2, &mut &[0x55; 10][..]) {
+        Err(Error::ExhaustedRetries) => {}
+        other => panic!("expected ExhaustedRetries, got {:?}", other),
+    }
+    assert_eq!(xmodem.initial_errors(), 3);
+    assert_eq!(xmodem.errors(), 0);
+
+    let mut sent = [0; 4];
+    p1.read_exact(&mut sent).unwrap();
+    assert_eq!(sent, [0x18, 0x18, 0x08, 0x08]);
+}
+
+#[test]
+fn ymodem_send_path() {
+    let data_out: Vec<u8> = (0..3000).map(|idx| ((idx + 7) * 13) as u8).collect();
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(&data_out).unwrap();
+    let path = file.path().to_path_buf();
+    let expected_name = path.file_name().unwrap().to_string_lossy().into_owned();
+
+    let (mut p1, mut p2) = loopback();
+    let handle = std::thread::spawn(move || {
+        let mut ymodem = Ymodem::new();
+        ymodem.send_path(&mut p1, &path).unwrap();
+    });
+    let mut ymodem = Ymodem::new();
+    let mut data_in = Vec::new();
+    let metadata = ymodem.recv(&mut p2, &mut data_in).unwrap();
+    handle.join().unwrap();
+
+    assert_eq!(metadata.file_name, expected_name);
+    assert_eq!(metadata.file_size, 3000);
+    assert_eq!(data_in, data_out);
+}
+
+#[test]
+fn ymodem_recv_drops_blocks_sent_before_retransmit() {
+    let (mut p1, mut p2) = loopback();
+    let mut corrupt = ymodem_block(1, &[0x55; 128]);
+    corrupt[10] ^= 0xFF;
+    p1.write_all(&ymodem_header(b"foo.bin", b"256")).unwrap();
+    p1.write_all(&corrupt).unwrap();
+    // Block 2 was already in flight when block 1 was NAK'd
+    p1.write_all(&ymodem_block(2, &[0x66; 128])).unwrap();
+    p1.write_all(&ymodem_block(1, &[0x55; 128])).unwrap();
+    p1.write_all(&ymodem_block(2, &[0x66; 128])).unwrap();
+    p1.write_all(&[0x04, 0x04]).unwrap();
+    p1.write_all(&ymodem_header(b"", b"")).unwrap();
+
+    let mut ymodem = Ymodem::new();
+    let mut data_in = Vec::new();
+    ymodem.recv(&mut p2, &mut data_in).unwrap();
+    assert_eq!(&data_in[..128], &[0x55; 128][..]);
+    assert_eq!(&data_in[128..], &[0x66; 128][..]);
+    assert_eq!(ymodem.errors(), 2);
+
+    // A single NAK for block 1 and nothing for the stale block 2
+    let mut replies = [0; 10];
+    p1.read_exact(&mut replies).unwrap();
+    assert_eq!(
+        replies,
+        [0x43, 0x06, 0x43, 0x15, 0x06, 0x06, 0x15, 0x06, 0x43, 0x06]
+    );
+}
+
+#[test]
+fn ymodem_send_buffered_short_reads() {
+    let data_out: Vec<u8> = (0..3000).map(|idx| idx as u8).collect();
+    let expected = data_out.clone();
+    let (mut p1, mut p2) = loopback();
+    let handle = std::thread::spawn(move || {
+        let mut ymodem = Ymodem::new();
+        ymodem
+            .send_buffered(&mut p1, &mut Trickle(&data_out), "foo.bin", 3000)
+            .unwrap();
+    });
+    let mut ymodem = Ymodem::new();
+    let mut data_in = Vec::new();
+    ymodem.recv(&mut p2, &mut data_in).unwrap();
+    handle.join().unwrap();
+
+    assert_eq!(data_in, expected);
+}
+
+#[test]
+fn xmodem_recv_acks_duplicate_block() {
+    let (mut p1, mut p2) = loopback();
+    p1.write_all(&xmodem_block(1, &[0x55; 128], false)).unwrap();
+    // The ACK for block 1 got lost, so the sender repeats it
+    p1.write_all(&xmodem_block(1, &[0x55; 128], false)).unwrap();
+    p1.write_all(&xmodem_block(2, &[0x66; 128], false)).unwrap();
+    p1.write_all(&[0x04]).unwrap();
+
+    let mut xmodem = Xmodem::new();
+    let mut data_in = Vec::new();
+    xmodem
+        .recv(&mut p2, &mut data_in, Checksum::Standard)
+        .unwrap();
+    assert_eq!(&data_in[..128], &[0x55; 128][..]);
+    assert_eq!(&data_in[128..], &[0x66; 128][..]);
+
+    let mut replies = [0; 5];
+    p1.read_exact(&mut replies).unwrap();
+    assert_eq!(replies, [0x15, 0x06, 0x06, 0x06, 0x06]);
+}
+
+#[test]
+fn xmodem_recv_cancels_out_of_sequence_block() {
+    let (mut p1, mut p2) = loopback();
+    p1.write_all(&xmodem_block(1, &[0x55; 128], false)).unwrap();
+    p1.write_all(&xmodem_block(3, &[0x66; 128], false)).unwrap();
+
+    let mut xmodem = Xmodem::new();
+    match xmodem.recv(&mut p2, &mut Vec::new(), Checksum::Standard) {
+        Err(Error::Canceled) => {}
+        other => panic!("expected Canceled, got {:?}", other),
+    }
+}
+
+#[test]
+fn xmodem_recv_cancels_block_zero_first() {
+    // Nothing has been received yet, so block 0 can't be a repeat
+    let (mut p1, mut p2) = loopback();
+    p1.write_all(&xmodem_block(0, &[0x55; 128], false)).unwrap();
+
+    let mut xmodem = Xmodem::new();
+    let mut data_in = Vec::new();
+    match xmodem.recv(&mut p2, &mut data_in, Checksum::Standard) {
+        Err(Error::Canceled) => {}
+        other => panic!("expected Canceled, got {:?}", other),
+    }
+    assert!(data_in.is_empty());
+}
+
+#[test]
+fn ymodem_send_batch() {
+    let (mut p1, mut p2) = loopback();
+    // For each file: ACK and C for the header, ACK for the block and NAK and ACK
+    // for the EOTs, followed by a C for the next header. Then ACK the end frame.
+    p1.write_all(&[0x43]).unwrap();
+    for _ in 0..2 {
+        p1.write_all(&[0x06, 0x43, 0x06, 0x15, 0x06, 0x43]).unwrap();
+    }
+    p1.write_all(&[0x06]).unwrap();
+
+    let files: Vec<(String, u64, Box<dyn Read>)> = vec![
+        ("foo.bin".to_string(), 10, Box::new(&[0x55; 10][..])),
+        ("bar.bin".to_string(), 20, Box::new(&[0x66; 20][..])),
+    ];
+    let mut ymodem = Ymodem::new();
+    assert_eq!(ymodem.send_batch(&mut p2, files).unwrap(), 2);
+    assert!(ymodem.session_complete());
+
+    let mut sent = [0; 2 * (133 + 133 + 2) + 133];
+    p1.read_exact(&mut sent).unwrap();
+    let (first, rest) = sent.split_at(268);
+    let (second, end_frame) = rest.split_at(268);
+    assert_eq!(&first[3..14], b"foo.bin\x0010\x00");
+    assert_eq!(&first[136..146], &[0x55; 10]);
+    assert_eq!(&second[3..14], b"bar.bin\x0020\x00");
+    assert_eq!(&second[136..156], &[0x66; 20]);
+    assert_eq!(&first[266..], &[0x04, 0x04]);
+    assert_eq!(&second[266..], &[0x04, 0x04]);
+    assert_eq!(end_frame, &ymodem_header(b"", b"")[..]);
+}
+
+#[test]
+fn ymodem_send_batch_without_files() {
+    let (mut p1, mut p2) = loopback();
+    // 'C' to start, then ACK the end frame
+    p1.write_all(&[0x43, 0x06]).unwrap();
+
+    let mut ymodem = Ymodem::new();
+    assert_eq!(ymodem.send_batch(&mut p2, Vec::new()).unwrap(), 0);
+    assert!(ymodem.session_complete());
+    drop(p2);
+
+    // Only the end frame is sent
+    let mut sent = [0; 133];
+    p1.read_exact(&mut sent).unwrap();
+    assert_eq!(&sent[..], &ymodem_header(b"", b"")[..]);
+    assert!(p1.read(&mut [0]).is_err());
+}
+
+#[test]
+fn ymodem_recv_exhausts_initial_errors_on_corrupt_header() {
+    let (mut p1, mut p2) = loopback();
+    let mut corrupt = ymodem_header(b"foo.bin", b"10");
+    corrupt[5] ^= 0xFF;
+    p1.write_all(&corrupt).unwrap();
+    p1.write_all(&corrupt).unwrap();
+
+    let mut ymodem = Ymodem::new();
+    ymodem.max_initial_errors = 1;
+    ymodem.can_count = 2;
+    match ymodem.recv(&mut p2, &mut Vec::new()) {
+        Err(Error::ExhaustedRetries) => {}
+        other => panic!("expected ExhaustedRetries, got {:?}", other),
+    }
+    assert_eq!(ymodem.initial_errors(), 2);
+    assert_eq!(ymodem.errors(), 0);
+
+    // 'C', NAK for the first header and the cancel sequence for the second
+    let mut replies = [0; 6];
+    p1.read_exact(&mut replies).unwrap();
+    assert_eq!(replies, [0x43, 0x15, 0x18, 0x18, 0x08, 0x08]);
+}
+
+#[test]
+fn ymodem_recv_eot_after_many_retransmissions() {
+    // More blocks arrive than the file has packets, which must not end the
+    // transfer before the EOTs and the end frame are handled
+    let mut corrupt = ymodem_block(1, &[0x55; 128]);
+    corrupt[10] ^= 0xFF;
+    let mut script = ymodem_header(b"foo.bin", b"100");
+    for _ in 0..5 {
+        script.extend(&corrupt);
+    }
+    script.extend(ymodem_block(1, &[0x55; 128]));
+    script.extend(&[0x04, 0x04]);
+    script.extend(ymodem_header(b"", b""));
+
+    let (metadata, data_in) = ymodem_recv_scripted(&script).unwrap();
+    assert_eq!(metadata.file_size, 100);
+    assert_eq!(data_in, vec![0x55; 100]);
+}
+
+#[test]
+fn xmodem_send_resends_block_on_nak() {
+    let (mut p1, mut p2) = loopback();
+    // NAK to start, NAK block 1 once, then ACK both blocks and the EOT
+    p1.write_all(&[0x15, 0x15, 0x06, 0x06, 0x06]).unwrap();
+
+    let data: Vec<u8> = (0..256).map(|idx| idx as u8).collect();
+    let mut xmodem = Xmodem::new();
+    xmodem.send(&mut p2, &mut &data[..]).unwrap();
+    assert_eq!(xmodem.errors(), 1);
+
+    let mut sent = [0; 3 * 132 + 1];
+    p1.read_exact(&mut sent).unwrap();
+    let block_1 = xmodem_block(1, &data[..128], false);
+    assert_eq!(&sent[..132], &block_1[..]);
+    assert_eq!(&sent[132..264], &block_1[..]);
+    assert_eq!(&sent[264..396], &xmodem_block(2, &data[128..], false)[..]);
+    assert_eq!(sent[396], 0x04);
+}
+
+#[test]
+fn ymodem_send_resends_block_on_nak() {
+    let (mut p1, mut p2) = loopback();
+    // 'C' to start, ACK and 'C' for the header, NAK then ACK for the block,
+    // NAK and ACK for the EOTs, 'C' and ACK for the end frame
+    p1.write_all(&[0x43, 0x06, 0x43, 0x15, 0x06, 0x15, 0x06, 0x43, 0x06])
+        .unwrap();
+
+    let mut ymodem = Ymodem::new();
+    ymodem
+        .send(&mut p2, &mut &[0x55; 10][..], "foo.bin", 10)
+        .unwrap();
+    assert_eq!(ymodem.errors(), 1);
+
+    let mut sent = [0; 3 * 133];
+    p1.read_exact(&mut sent).unwrap();
+    let mut block = vec![0x55; 10];
+    block.resize(128, 0x1a);
+    let block = ymodem_block(1, &block);
+    assert_eq!(&sent[133..266], &block[..]);
+    assert_eq!(&sent[266..], &block[..]);
+}
+
+#[test]
+fn ymodem_send_file_name_with_space() {
+    let (mut p1, mut p2) = loopback();
+    let handle = std::thread::spawn(move || {
+        let mut ymodem = Ymodem::new();
+        ymodem
+            .send(&mut p1, &mut &[0x55; 10][..], "my file.bin", 10)
+            .unwrap();
+    });
+    let mut ymodem = Ymodem::new();
+    let metadata = ymodem.recv(&mut p2, &mut Vec::new()).unwrap();
+    handle.join().unwrap();
+    assert_eq!(metadata.file_name, "my file.bin");
+}
+
+#[test]
+fn ymodem_send_rejects_non_ascii_file_name() {
+    let long_name = "a".repeat(200);
+    for name in &["my r\u{e9}sum\u{e9}.txt", "foo\0bar", &long_name] {
+        let (mut p1, mut p2) = loopback();
+        p1.write_all(&[0x43]).unwrap();
+
+        let mut ymodem = Ymodem::new();
+        ymodem.can_count = 2;
+        match ymodem.send(&mut p2, &mut &[0x55; 10][..], *name, 10) {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), ErrorKind::InvalidInput),
+            other => panic!("expected InvalidInput error, got {:?}", other),
+        }
+
+        // Nothing but the cancel sequence is sent
+        let mut sent = [0; 4];
+        p1.read_exact(&mut sent).unwrap();
+        assert_eq!(sent, [0x18, 0x18, 0x08, 0x08]);
+    }
+}
+
+#[test]
+fn ymodem_recv_unknown_size() {
+    for size in &[&b""[..], b"0", b"0 14567716601"] {
+        let mut script = ymodem_header(b"foo.bin", size);
+        script.extend(ymodem_block(1, &[0x55; 1024]));
+        script.extend(ymodem_block(2, &[0x66; 128]));
+        script.extend(&[0x04, 0x04]);
+        script.extend(ymodem_header(b"", b""));
+
+        let (metadata, data_in) = ymodem_recv_scripted(&script).unwrap();
+        assert_eq!(metadata.file_size, 0);
+        assert_eq!(data_in.len(), 1024 + 128);
+        assert_eq!(&data_in[1024..], &[0x66; 128][..]);
+    }
+}
+
+#[test]
+fn ymodem_send_batch_resets_errors_per_file() {
+    let (mut p1, mut p2) = loopback();
+    // Each file's block is NAK'd once before being ACK'd
+    p1.write_all(&[0x43]).unwrap();
+    for _ in 0..2 {
+        p1.write_all(&[0x06, 0x43, 0x15, 0x06, 0x15, 0x06, 0x43])
+            .unwrap();
+    }
+    p1.write_all(&[0x06]).unwrap();
+
+    let files: Vec<(String, u64, Box<dyn Read>)> = vec![
+        ("foo.bin".to_string(), 10, Box::new(&[0x55; 10][..])),
+        ("bar.bin".to_string(), 20, Box::new(&[0x66; 20][..])),
+    ];
+    let mut ymodem = Ymodem::new();
+    ymodem.max_errors = 2;
+    assert_eq!(ymodem.send_batch(&mut p2, files).unwrap(), 2);
+    assert_eq!(ymodem.errors(), 1);
+}
+
+/// A device that has data to read but fails every write.
+struct ReadOnly<'a>(&'a [u8]);
+
+impl<'a> Read for ReadOnly<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<'a> Write for ReadOnly<'a> {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(ErrorKind::BrokenPipe, "write failed"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn recv_propagates_write_errors() {
+    let block = xmodem_block(1, &[0x55; 128], false);
+    let mut xmodem = Xmodem::new();
+    match xmodem.recv(&mut ReadOnly(&block), &mut Vec::new(), Checksum::Standard) {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), ErrorKind::BrokenPipe),
+        other => panic!("expected BrokenPipe error, got {:?}", other),
+    }
+
+    let header = ymodem_header(b"foo.bin", b"10");
+    let mut ymodem = Ymodem::new();
+    match ymodem.recv(&mut ReadOnly(&header), &mut Vec::new()) {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), ErrorKind::BrokenPipe),
+        other => panic!("expected BrokenPipe error, got {:?}", other),
+    }
+}
+
+#[test]
+fn send_propagates_write_errors() {
+    let mut xmodem = Xmodem::new();
+    match xmodem.send(&mut ReadOnly(&[0x15]), &mut &[0x55; 10][..]) {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), ErrorKind::BrokenPipe),
+        other => panic!("expected BrokenPipe error, got {:?}", other),
+    }
+
+    let mut ymodem = Ymodem::new();
+    match ymodem.send(&mut ReadOnly(&[0x43]), &mut &[0x55; 10][..], "foo.bin", 10) {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), ErrorKind::BrokenPipe),
+        other => panic!("expected BrokenPipe error, got {:?}", other),
+    }
+}
+
+#[test]
+fn xmodem_send_batch() {
+    let (mut p1, mut p2) = loopback();
+    // The first transfer uses the standard checksum and the second CRC16
+    p1.write_all(&[0x15, 0x06, 0x06, 0x43, 0x06, 0x06]).unwrap();
+
+    let streams: Vec<Box<dyn Read>> = vec![Box::new(&[0x55; 10][..]), Box::new(&[0x66; 20][..])];
+    let mut xmodem = Xmodem::new();
+    assert_eq!(xmodem.send_batch(&mut p2, streams).unwrap(), 2);
+
+    let mut sent = [0; 132 + 1 + 133 + 1];
+    p1.read_exact(&mut sent).unwrap();
+    let mut first = vec![0x55; 10];
+    first.resize(128, 0x1a);
+    let mut second = vec![0x66; 20];
+    second.resize(128, 0x1a);
+    assert_eq!(&sent[..132], &xmodem_block(1, &first, false)[..]);
+    assert_eq!(sent[132], 0x04);
+    assert_eq!(
+        &sent[133..266],
+        &PacketBuilder::new(1, Checksum::CRC16).build_128(&second)[..]
+    );
+    assert_eq!(sent[266], 0x04);
+}
+
+#[test]
+fn ymodem_recv_to_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("app.bin"), b"old").unwrap();
+
+    let mut script = ymodem_header(b"../firmware/app.bin", b"1100");
+    script.extend(ymodem_block(1, &[0x55; 1024]));
+    script.extend(ymodem_block(2, &[0x66; 128]));
+    script.extend(&[0x04, 0x04]);
+    script.extend(ymodem_header(b"", b""));
+    let (mut p1, mut p2) = loopback();
+    p1.write_all(&script).unwrap();
+
+    let mut ymodem = Ymodem::new();
+    let metadata = ymodem.recv_to_dir(&mut p2, dir.path()).unwrap();
+    assert_eq!(metadata.file_size, 1100);
+    let data_in = std::fs::read(dir.path().join("app.bin")).unwrap();
+    assert!(!dir.path().join("app.bin.part").exists());
+
+    assert_eq!(data_in.len(), 1100);
+    assert_eq!(&data_in[..1024], &[0x55; 1024][..]);
+    assert_eq!(&data_in[1024..], &[0x66; 76][..]);
+}
+
+#[test]
+fn ymodem_recv_to_dir_keeps_existing_file_on_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("app.bin"), b"old").unwrap();
+
+    // The sender goes quiet after the first block
+    let mut script = ymodem_header(b"app.bin", b"1100");
+    script.extend(ymodem_block(1, &[0x55; 1024]));
+    let mut dev = Scripted {
+        input: &script,
+        output: Vec::new(),
+    };
+
+    let mut ymodem = Ymodem::new();
+    match ymodem.recv_to_dir(&mut dev, dir.path()) {
+        Err(Error::ExhaustedRetries) => {}
+        other => panic!("expected ExhaustedRetries, got {:?}", other),
+    }
+    assert_eq!(std::fs::read(dir.path().join("app.bin")).unwrap(), b"old");
+    assert!(!dir.path().join("app.bin.part").exists());
+}
+
+#[test]
+fn ymodem_recv_to_dir_rejects_parent_dir() {
+    let (mut p1, mut p2) = loopback();
+    p1.write_all(&ymodem_header(b"foo/..", b"10")).unwrap();
+
+    let mut ymodem = Ymodem::new();
+    let dir = tempfile::tempdir().unwrap();
+    match ymodem.recv_to_dir(&mut p2, dir.path()) {
+        Err(Error::ParseError(_)) => {}
+        other => panic!("expected ParseError, got {:?}", other),
+    }
+}
+
+#[test]
+fn xmodem_send_onek_g_keeps_block_length() {
+    let (mut p1, mut p2) = loopback();
+    // A G receiver, then a standard checksum receiver
+    p1.write_all(&[0x47, 0x06, 0x15, 0x06, 0x06]).unwrap();
+
+    let mut xmodem = Xmodem::new();
+    xmodem.send(&mut p2, &mut &[0x55; 10][..]).unwrap();
+    xmodem.send(&mut p2, &mut &[0x66; 10][..]).unwrap();
+    assert!(matches!(xmodem.block_length, BlockLength::Standard));
+
+    // A streamed 1k block and EOT, then a 128-byte block and EOT
+    let mut sent = [0; 1029 + 1 + 132 + 1];
+    p1.read_exact(&mut sent).unwrap();
+    assert_eq!(sent[0], 0x02);
+    assert_eq!(sent[1029], 0x04);
+    assert_eq!(sent[1030], 0x01);
+    assert_eq!(sent[1162], 0x04);
+}
+
+#[test]
+fn ymodem_recv_acks_repeated_block() {
+    // Block 1 is sent again as if our ACK for it was lost
+    let mut script = ymodem_header(b"foo.bin", b"200");
+    script.extend(ymodem_block(1, &[0x55; 128]));
+    script.extend(ymodem_block(1, &[0x55; 128]));
+    script.extend(ymodem_block(2, &[0x66; 128]));
+    script.extend(&[0x04, 0x04]);
+    script.extend(ymodem_header(b"", b""));
+
+    let (mut p1, mut p2) = loopback();
+    p1.write_all(&script).unwrap();
+    let mut ymodem = Ymodem::new();
+    let mut data_in = Vec::new();
+    ymodem.recv(&mut p2, &mut data_in).unwrap();
+    assert_eq!(ymodem.errors(), 0);
+    assert_eq!(&data_in[..128], &[0x55; 128][..]);
+    assert_eq!(&data_in[128..], &[0x66; 72][..]);
+
+    // 'C', ACK and 'C' for the header, then an ACK for each block, repeat included
+    let mut replies = [0; 6];
+    p1.read_exact(&mut replies).unwrap();
+    assert_eq!(replies, [0x43, 0x06, 0x43, 0x06, 0x06, 0x06]);
+}
+
+/// A device that reads from a script, times out once it runs dry, and keeps everything written to it.
+struct Scripted<'a> {
+    input: &'a [u8],
+    output: Vec<u8>,
+}
+
+impl<'a> Read for Scripted<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.input.is_empty() {
+            return Err(io::Error::new(ErrorKind::TimedOut, "timed out"));
+        }
+        self.input.read(buf)
+    }
+}
+
+impl<'a> Write for Scripted<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn ymodem_recv_header_retry_times_out() {
+    let mut corrupt = ymodem_header(b"foo.bin", b"10");
+    corrupt[5] ^= 0xFF;
+    let mut dev = Scripted {
+        input: &corrupt,
+        output: Vec::new(),
+    };
+
+    let mut ymodem = Ymodem::new();
+    ymodem.max_initial_errors = 2;
+    ymodem.can_count = 2;
+    match ymodem.recv(&mut dev, &mut Vec::new()) {
+        Err(Error::ExhaustedRetries) => {}
+        other => panic!("expected ExhaustedRetries, got {:?}", other),
+    }
+    assert_eq!(ymodem.initial_errors(), 3);
+
+    // 'C', NAK for the header, NAK again after the first timeout, then the cancel sequence
+    assert_eq!(dev.output, [0x43, 0x15, 0x15, 0x18, 0x18, 0x08, 0x08]);
+}
+
+#[test]
+fn ymodem_send_resends_frames_on_nak() {
+    let (mut p1, mut p2) = loopback();
+    // 'C' to start, NAK and ACK for the header, 'C', ACK for the block,
+    // NAK and ACK for the EOTs, then 'C', NAK and ACK for the end frame
+    p1.write_all(&[0x43, 0x15, 0x06, 0x43, 0x06, 0x15, 0x06, 0x43, 0x15, 0x06])
+        .unwrap();
+
+    let mut ymodem = Ymodem::new();
+    ymodem
+        .send(&mut p2, &mut &[0x55; 10][..], "foo.bin", 10)
+        .unwrap();
+    assert!(ymodem.session_complete());
+
+    // The header twice, the block, the two EOTs and the end frame twice
+    let mut sent = [0; 133 * 3 + 2 + 133 * 2];
+    p1.read_exact(&mut sent).unwrap();
+    assert_eq!(&sent[..133], &ymodem_header(b"foo.bin", b"10")[..]);
+    assert_eq!(&sent[133..266], &sent[..133]);
+    assert_eq!(&sent[399..401], &[0x04, 0x04]);
+    assert_eq!(&sent[401..534], &ymodem_header(b"", b"")[..]);
+    assert_eq!(&sent[534..], &sent[401..534]);
+}
+
+#[test]
+fn ymodem_recv_rejects_header_as_end_frame() {
+    // A second file's header where the end frame should be
+    let mut script = ymodem_header(b"foo.bin", b"10");
+    script.extend(ymodem_block(1, &[0x55; 128]));
+    script.extend(&[0x04, 0x04]);
+    script.extend(ymodem_header(b"bar.bin", b"20"));
+
+    let (mut p1, mut p2) = loopback();
+    p1.write_all(&script).unwrap();
+    let mut ymodem = Ymodem::new();
+    ymodem.can_count = 2;
+    match ymodem.recv(&mut p2, &mut Vec::new()) {
+        Err(Error::ParseError(msg)) => assert!(msg.contains("end frame")),
+        other => panic!("expected ParseError, got {:?}", other),
+    }
+
+    // 'C', ACK and 'C' for the header, ACK for the block, NAK, ACK and 'C'
+    // for the EOTs, then the cancel sequence instead of an ACK
+    let mut replies = [0; 11];
+    p1.read_exact(&mut replies).unwrap();
+    assert_eq!(
+        replies,
+        [0x43, 0x06, 0x43, 0x06, 0x15, 0x06, 0x43, 0x18, 0x18, 0x08, 0x08]
+    );
+}
+
+#[test]
+fn block_length_discriminants_match_size() {
+    assert_eq!(BlockLength::Standard as usize, BlockLength::Standard.size());
+    assert_eq!(BlockLength::OneK as usize, BlockLength::OneK.size());
+    assert_eq!(BlockLength::OneKG.size(), 1024);
+}
@@ -0,0 +1,106 @@
+//! Test the packet utilities
+extern crate ymodem;
+
+use ymodem::xmodem::{
+    calc_checksum, calc_crc, crc16_table, validate_packet, Checksum, PacketBuilder, PacketError,
+};
+
+#[test]
+fn build_standard_packet() {
+    let data = [0x55; 100];
+    let mut packet = PacketBuilder::new(7, Checksum::Standard);
+    packet.pad_byte = 0x00;
+    let buf = packet.build_128(&data);
+    assert_eq!(buf.len(), 132);
+    assert_eq!(&buf[..3], &[0x01, 7, 0xF8]);
+    assert_eq!(&buf[3..103], &data[..]);
+    assert!(buf[103..131].iter().all(|&b| b == 0x00));
+    assert_eq!(buf[131], calc_checksum(&buf[3..131]));
+}
+
+#[test]
+fn build_crc_1k_packet() {
+    let data = [0x55; 1024];
+    let buf = PacketBuilder::new(1, Checksum::CRC16).build_1024(&data);
+    let crc = calc_crc(&data);
+    assert_eq!(buf.len(), 1029);
+    assert_eq!(&buf[..3], &[0x02, 1, 0xFE]);
+    assert_eq!(&buf[1027..], &[(crc >> 8) as u8, crc as u8]);
+}
+
+#[test]
+#[should_panic]
+fn build_oversized_packet() {
+    PacketBuilder::new(1, Checksum::CRC16).build_128(&[0; 129]);
+}
+
+#[test]
+fn validate_standard_packet() {
+    let data = [0x55; 128];
+    let buf = PacketBuilder::new(3, Checksum::Standard).build_128(&data);
+    let info = validate_packet(&buf, Checksum::Standard).unwrap();
+    assert_eq!(info.header, 0x01);
+    assert_eq!(info.block_num, 3);
+    assert_eq!(info.data, &data[..]);
+}
+
+#[test]
+fn validate_crc_1k_packet() {
+    let data: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+    let buf = PacketBuilder::new(255, Checksum::CRC16).build_1024(&data);
+    let info = validate_packet(&buf, Checksum::CRC16).unwrap();
+    assert_eq!(info.header, 0x02);
+    assert_eq!(info.block_num, 255);
+    assert_eq!(info.data, &data[..]);
+}
+
+#[test]
+fn validate_packet_errors() {
+    let data = [0x55; 128];
+    assert_eq!(
+        validate_packet(&[], Checksum::Standard),
+        Err(PacketError::Empty)
+    );
+    assert_eq!(
+        validate_packet(&[0x04], Checksum::Standard),
+        Err(PacketError::InvalidHeader(0x04))
+    );
+
+    let buf = PacketBuilder::new(1, Checksum::Standard).build_128(&data);
+    assert_eq!(
+        validate_packet(&buf, Checksum::CRC16),
+        Err(PacketError::InvalidLength {
+            expected: 133,
+            actual: 132
+        })
+    );
+
+    let mut bad_complement = buf.clone();
+    bad_complement[2] = 0x00;
+    assert_eq!(
+        validate_packet(&bad_complement, Checksum::Standard),
+        Err(PacketError::BlockNumberMismatch {
+            block_num: 1,
+            complement: 0
+        })
+    );
+
+    let mut bad_data = buf.clone();
+    bad_data[10] ^= 0xFF;
+    assert_eq!(
+        validate_packet(&bad_data, Checksum::Standard),
+        Err(PacketError::ChecksumMismatch)
+    );
+}
+
+#[test]
+fn crc16_table_matches_calc_crc() {
+    let table = crc16_table();
+    assert_eq!(table[1], 0x1021);
+    let data: Vec<u8> = (0..=255).collect();
+    let crc = data.iter().fold(0u16, |crc, &b| {
+        (crc << 8) ^ table[((crc >> 8) as u8 ^ b) as usize]
+    });
+    assert_eq!(crc, calc_crc(&data));
+    assert_eq!(calc_crc(b"123456789"), 0x31C3);
+}
@@ -0,0 +1,11 @@
+//! Compile-time checks of the traits implemented by the configurations
+#[macro_use]
+extern crate static_assertions;
+extern crate ymodem;
+
+use ymodem::xmodem::Xmodem;
+use ymodem::ymodem::{Error, Ymodem};
+
+assert_impl_all!(Xmodem: Send, Sync, Copy, Clone);
+assert_impl_all!(Ymodem: Send, Sync, Copy, Clone);
+assert_impl_all!(Error: std::error::Error, Send, Sync);